@@ -0,0 +1,200 @@
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep, timeout};
+
+use super::peer::Peer;
+use super::stats::TransferStats;
+use super::tracker::{local_ipv6_addr, AnnounceList, Event, TrackerRequest, TrackerResponse};
+use super::types::{InfoHash, PeerId};
+
+// Used until a tracker has told us how often it wants to hear from us.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// How long the `stopped` announce may hold up shutdown.
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Announces the download to its trackers, then keeps the swarm fresh for its
+// lifetime by re-announcing on the interval they ask for.  Every peer a
+// tracker hands out is passed on to the session, which knows which ones it's
+// already connected to.
+#[derive(Debug)]
+pub struct Announcer {
+    trackers: AnnounceList,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    key: u32,
+    // The port we're listening on, when we are.
+    port: Option<u16>,
+    // Looked up once, it takes a socket to find.
+    ipv6: Option<Ipv6Addr>,
+    stats: Arc<TransferStats>,
+    interval: Duration,
+    peer_tx: UnboundedSender<Peer>,
+}
+
+impl Announcer {
+    pub fn new(
+        trackers: AnnounceList,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        key: u32,
+        stats: Arc<TransferStats>,
+        peer_tx: UnboundedSender<Peer>,
+    ) -> Self {
+        Self {
+            trackers,
+            info_hash,
+            peer_id,
+            key,
+            port: None,
+            ipv6: local_ipv6_addr(),
+            stats,
+            interval: DEFAULT_INTERVAL,
+            peer_tx,
+        }
+    }
+
+    pub fn with_port(mut self, port: Option<u16>) -> Self {
+        self.port = port;
+        self
+    }
+
+    // Announces `Started`, then runs until the session sends `Stopped` or
+    // drops its end of the events channel, announcing `Completed` as soon as
+    // it is received and re-announcing whenever the interval elapses in
+    // between.
+    pub async fn run(mut self, mut events: UnboundedReceiver<Event>) {
+        self.announce(Some(Event::Started)).await;
+        loop {
+            tokio::select! {
+                _ = sleep(self.interval) => self.announce(None).await,
+                event = events.recv() => match event {
+                    Some(Event::Stopped) | None => {
+                        // don't hold up shutdown for trackers that are down
+                        let stopped = self.announce(Some(Event::Stopped));
+                        if timeout(STOPPED_TIMEOUT, stopped).await.is_err() {
+                            println!("gave up announcing stopped");
+                        }
+                        break;
+                    }
+                    Some(event) => self.announce(Some(event)).await,
+                }
+            }
+        }
+    }
+
+    async fn announce(&mut self, event: Option<Event>) {
+        // peers can only come from elsewhere, e.g. a magnet link
+        if self.trackers.is_empty() {
+            return;
+        }
+        let mut req =
+            TrackerRequest::new_from_stats(self.info_hash, self.peer_id, self.key, &self.stats)
+                .with_ipv6(self.ipv6);
+        if let Some(port) = self.port {
+            req = req.with_port(port);
+        }
+        if let Some(event) = event {
+            req = req.with_event(event);
+        }
+        let mut responses = Vec::new();
+        for result in self.trackers.announce(&req).await {
+            match result {
+                Ok(res) => {
+                    if let Some(warning) = &res.warning_message {
+                        println!("tracker warning: {}", warning);
+                    }
+                    responses.push(res);
+                }
+                Err(e) => println!("announce failed: {}", e),
+            }
+        }
+
+        if let Some(interval) = next_interval(&responses) {
+            self.interval = interval;
+        }
+        for res in responses {
+            for peer in res.to_peers() {
+                // the session hanging up just means it is shutting down
+                let _ = self.peer_tx.send(peer);
+            }
+        }
+    }
+}
+
+// When to announce again, going by whichever tier wants to hear from us
+// soonest.  None if no tracker answered with a usable interval.
+fn next_interval(responses: &[TrackerResponse]) -> Option<Duration> {
+    responses
+        .iter()
+        .map(|res| res.next_interval())
+        .min()
+        .filter(|interval| *interval > 0)
+        .map(|interval| Duration::from_secs(interval as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::udp_tracker::tests::StandIn;
+    use std::time::Instant;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn response(body: &[u8]) -> TrackerResponse {
+        serde_bencode::from_bytes(body).unwrap()
+    }
+
+    #[test]
+    fn soonest_interval_wins() {
+        let responses = [
+            response(b"d8:intervali1800ee"),
+            response(b"d8:intervali900ee"),
+        ];
+        assert_eq!(next_interval(&responses), Some(Duration::from_secs(900)));
+    }
+
+    #[test]
+    fn min_interval_is_respected() {
+        let responses = [
+            response(b"d8:intervali1800ee"),
+            response(b"d8:intervali60e12:min intervali1200ee"),
+        ];
+        assert_eq!(next_interval(&responses), Some(Duration::from_secs(1200)));
+    }
+
+    #[test]
+    fn no_usable_interval() {
+        assert_eq!(next_interval(&[]), None);
+        assert_eq!(next_interval(&[response(b"d8:intervali0ee")]), None);
+    }
+
+    #[tokio::test]
+    async fn reannounces_on_the_interval() {
+        let (stand_in, tracker) = StandIn::bind().await;
+        let trackers = AnnounceList::new(&[vec![tracker.url().to_string()]]);
+        let (peer_tx, _peer_rx) = unbounded_channel();
+        let announcer = Announcer::new(
+            trackers,
+            [1; 20],
+            [2; 20],
+            0,
+            Arc::new(TransferStats::new(1000)),
+            peer_tx,
+        );
+        let (event_tx, event_rx) = unbounded_channel();
+        let announcer = tokio::spawn(announcer.run(event_rx));
+
+        // started, then nothing in particular a second later
+        stand_in.accept_connect().await;
+        assert_eq!(stand_in.accept_announce(1).await, 2);
+        let started = Instant::now();
+        assert_eq!(stand_in.accept_announce(1).await, 0);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        event_tx.send(Event::Stopped).unwrap();
+        assert_eq!(stand_in.accept_announce(1).await, 3);
+        announcer.await.unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+
+// serde_bencode only hands back decoded values, but a few places need to know
+// exactly which bytes a value occupied in the original buffer: the info-hash
+// is the SHA-1 of the info dictionary as it was encoded, not as we would
+// re-encode it.  These helpers walk the encoding without decoding it.
+
+// Deeply nested lists/dicts are never legitimate in metainfo, this just keeps
+// a malicious file from blowing the stack.
+const MAX_DEPTH: usize = 64;
+
+// Returns the number of bytes taken up by the bencoded value that starts at
+// the beginning of `buf`.  Anything after the value is ignored.
+pub fn value_len(buf: &[u8]) -> Result<usize> {
+    value_end(buf, 0, 0)
+}
+
+// Finds the byte range of the value stored under `key` in the dictionary that
+// starts at the beginning of `buf`, or `None` if there is no such key.
+pub fn dict_value_span(buf: &[u8], key: &[u8]) -> Result<Option<Range<usize>>> {
+    if buf.first() != Some(&b'd') {
+        return Err(anyhow!("expected a dictionary"));
+    }
+    let mut pos = 1;
+    while buf.get(pos) != Some(&b'e') {
+        let (dict_key, value_start) = byte_string(buf, pos)?;
+        let value_end = value_end(buf, value_start, 1)?;
+        if dict_key == key {
+            return Ok(Some(value_start..value_end));
+        }
+        pos = value_end;
+    }
+    Ok(None)
+}
+
+fn value_end(buf: &[u8], pos: usize, depth: usize) -> Result<usize> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("bencode nested too deeply"));
+    }
+    match buf.get(pos) {
+        Some(b'i') => {
+            let end = find(buf, pos + 1, b'e')?;
+            Ok(end + 1)
+        }
+        Some(b'l') => {
+            let mut pos = pos + 1;
+            while buf.get(pos) != Some(&b'e') {
+                pos = value_end(buf, pos, depth + 1)?;
+            }
+            Ok(pos + 1)
+        }
+        Some(b'd') => {
+            let mut pos = pos + 1;
+            while buf.get(pos) != Some(&b'e') {
+                let (_, value_start) = byte_string(buf, pos)?;
+                pos = value_end(buf, value_start, depth + 1)?;
+            }
+            Ok(pos + 1)
+        }
+        Some(b'0'..=b'9') => {
+            let (_, end) = byte_string(buf, pos)?;
+            Ok(end)
+        }
+        Some(c) => Err(anyhow!("unexpected byte {:#04x} at offset {}", c, pos)),
+        None => Err(anyhow!("unexpected end of input at offset {}", pos)),
+    }
+}
+
+// Parses a `<len>:<bytes>` string starting at `pos`, returning the string and
+// the offset just past it.
+fn byte_string(buf: &[u8], pos: usize) -> Result<(&[u8], usize)> {
+    let colon = find(buf, pos, b':')?;
+    let len: usize = std::str::from_utf8(&buf[pos..colon])?
+        .parse()
+        .map_err(|_| anyhow!("invalid string length at offset {}", pos))?;
+    let start = colon + 1;
+    let end = start
+        .checked_add(len)
+        .filter(|end| *end <= buf.len())
+        .ok_or_else(|| anyhow!("string at offset {} runs past end of input", pos))?;
+    Ok((&buf[start..end], end))
+}
+
+fn find(buf: &[u8], from: usize, needle: u8) -> Result<usize> {
+    buf.get(from..)
+        .and_then(|rest| rest.iter().position(|b| *b == needle))
+        .map(|i| from + i)
+        .ok_or_else(|| anyhow!("unexpected end of input at offset {}", from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_value_span() {
+        let buf = b"d8:announce3:url4:infod4:name1:xe3:numi42ee";
+        let span = dict_value_span(buf, b"info").unwrap().unwrap();
+        assert_eq!(&buf[span], b"d4:name1:xe");
+        let span = dict_value_span(buf, b"num").unwrap().unwrap();
+        assert_eq!(&buf[span], b"i42e");
+    }
+
+    #[test]
+    fn span_is_taken_as_encoded() {
+        // keys out of order, re-encoding would sort them
+        let buf = b"d4:infod1:bi1e1:ai2eee";
+        let span = dict_value_span(buf, b"info").unwrap().unwrap();
+        assert_eq!(&buf[span], b"d1:bi1e1:ai2ee");
+    }
+
+    #[test]
+    fn missing_key() {
+        assert_eq!(dict_value_span(b"d1:ai1ee", b"info").unwrap(), None);
+        assert_eq!(dict_value_span(b"de", b"info").unwrap(), None);
+    }
+
+    #[test]
+    fn nested_keys_are_not_matched() {
+        let buf = b"d5:outerd4:infoi1eee";
+        assert_eq!(dict_value_span(buf, b"info").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(dict_value_span(b"li1ee", b"info").is_err());
+        assert!(dict_value_span(b"", b"info").is_err());
+        assert!(dict_value_span(b"d4:info", b"info").is_err());
+        assert!(dict_value_span(b"d4:info9:short", b"info").is_err());
+        assert!(dict_value_span(b"d1:ax1:be", b"b").is_err());
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let mut buf = b"d4:info".to_vec();
+        buf.extend(vec![b'l'; MAX_DEPTH + 2]);
+        buf.extend(vec![b'e'; MAX_DEPTH + 3]);
+        assert!(dict_value_span(&buf, b"info").is_err());
+    }
+
+    #[test]
+    fn value_len_ignores_trailing_bytes() {
+        assert_eq!(value_len(b"i42etrailing").unwrap(), 4);
+        assert_eq!(value_len(b"3:abcd").unwrap(), 5);
+        assert_eq!(value_len(b"l1:ai1ee").unwrap(), 8);
+    }
+}
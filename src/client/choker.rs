@@ -0,0 +1,298 @@
+use super::scheduler::WorkerId;
+
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// How often the choker reconsiders who to upload to.
+pub(crate) const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// Peers unchoked for their rates, on top of the optimistic unchoke.
+const UNCHOKE_SLOTS: usize = 4;
+// The optimistic unchoke moves on every third round, i.e. every 30 s.
+const OPTIMISTIC_ROUNDS: usize = 3;
+// Connections younger than this are three times as likely to be picked for
+// the optimistic unchoke, they have nothing to offer us yet.
+const NEW_PEER_AGE: Duration = Duration::from_secs(30);
+
+// What a worker and the choker know about the worker's peer.  The worker
+// counts bytes and keeps track of the peer's interest, the choker decides
+// whether the peer should be choked and the worker sees that it is.
+#[derive(Debug)]
+pub(crate) struct PeerState {
+    // payload bytes received from the peer
+    downloaded: AtomicU64,
+    // payload bytes sent to the peer
+    uploaded: AtomicU64,
+    interested: AtomicBool,
+    choke: AtomicBool,
+    changed: Notify,
+}
+
+impl PeerState {
+    pub(crate) fn new() -> Self {
+        PeerState {
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            interested: AtomicBool::new(false),
+            // everyone starts out choked
+            choke: AtomicBool::new(true),
+            changed: Notify::new(),
+        }
+    }
+
+    pub(crate) fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_interested(&self, interested: bool) {
+        self.interested.store(interested, Ordering::Relaxed);
+    }
+
+    // Whether the choker wants the peer choked.
+    pub(crate) fn should_choke(&self) -> bool {
+        self.choke.load(Ordering::Relaxed)
+    }
+
+    // Resolves once the choker has changed its mind about the peer.
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    fn set_choke(&self, choke: bool) {
+        if self.choke.swap(choke, Ordering::Relaxed) != choke {
+            self.changed.notify_one();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChokerPeer {
+    state: Arc<PeerState>,
+    connected_at: Instant,
+    // the counters as of the last round, to work out rates from
+    last_downloaded: u64,
+    last_uploaded: u64,
+}
+
+// Tit-for-tat: we upload to the peers that give us the most, or that take
+// the most once we're seeding, plus one picked at random so newcomers get a
+// chance to prove themselves.
+#[derive(Debug, Default)]
+pub(crate) struct Choker {
+    peers: HashMap<WorkerId, ChokerPeer>,
+    optimistic: Option<WorkerId>,
+    rounds: usize,
+}
+
+impl Choker {
+    pub(crate) fn add(&mut self, worker: WorkerId, state: Arc<PeerState>) {
+        self.peers.insert(
+            worker,
+            ChokerPeer {
+                state,
+                connected_at: Instant::now(),
+                last_downloaded: 0,
+                last_uploaded: 0,
+            },
+        );
+    }
+
+    pub(crate) fn remove(&mut self, worker: WorkerId) {
+        self.peers.remove(&worker);
+        if self.optimistic == Some(worker) {
+            self.optimistic = None;
+        }
+    }
+
+    pub(crate) fn run_round(&mut self, seeding: bool) {
+        // bytes moved since the last round, which is as good as a rate when
+        // rounds are evenly spaced
+        let mut rates: Vec<(WorkerId, u64)> = Vec::with_capacity(self.peers.len());
+        for (worker, peer) in self.peers.iter_mut() {
+            let downloaded = peer.state.downloaded.load(Ordering::Relaxed);
+            let uploaded = peer.state.uploaded.load(Ordering::Relaxed);
+            let rate = if seeding {
+                uploaded - peer.last_uploaded
+            } else {
+                downloaded - peer.last_downloaded
+            };
+            peer.last_downloaded = downloaded;
+            peer.last_uploaded = uploaded;
+            if peer.state.interested.load(Ordering::Relaxed) {
+                rates.push((*worker, rate));
+            }
+        }
+        rates.sort_by_key(|(_, rate)| std::cmp::Reverse(*rate));
+        let mut unchoked: Vec<WorkerId> = rates
+            .iter()
+            .take(UNCHOKE_SLOTS)
+            .map(|(worker, _)| *worker)
+            .collect();
+
+        let rotate = self.rounds.is_multiple_of(OPTIMISTIC_ROUNDS);
+        self.rounds += 1;
+        let keep = self
+            .optimistic
+            .filter(|worker| !rotate && !unchoked.contains(worker))
+            .filter(|worker| rates.iter().any(|(interested, _)| interested == worker));
+        self.optimistic = keep.or_else(|| {
+            let mut candidates = Vec::new();
+            for (worker, _) in rates.iter().skip(UNCHOKE_SLOTS) {
+                let weight = if self.peers[worker].connected_at.elapsed() < NEW_PEER_AGE {
+                    3
+                } else {
+                    1
+                };
+                candidates.extend(std::iter::repeat_n(*worker, weight));
+            }
+            candidates.choose(&mut rand::thread_rng()).copied()
+        });
+        unchoked.extend(self.optimistic);
+
+        for (worker, peer) in &self.peers {
+            peer.state.set_choke(!unchoked.contains(worker));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::torrent::test_helpers::workers;
+
+    // Adds `count` new peers to the choker.
+    async fn peers(choker: &mut Choker, count: usize) -> Vec<(WorkerId, Arc<PeerState>)> {
+        let mut peers = Vec::new();
+        for worker in workers(count).await {
+            let state = Arc::new(PeerState::new());
+            choker.add(worker, state.clone());
+            peers.push((worker, state));
+        }
+        peers
+    }
+
+    fn unchoked(peers: &[(WorkerId, Arc<PeerState>)]) -> Vec<bool> {
+        peers
+            .iter()
+            .map(|(_, state)| !state.should_choke())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn uninterested_peers_stay_choked() {
+        let mut choker = Choker::default();
+        let peers = peers(&mut choker, 2).await;
+        peers[0].1.add_downloaded(1000);
+        choker.run_round(false);
+        assert_eq!(unchoked(&peers), [false, false]);
+        peers[1].1.set_interested(true);
+        choker.run_round(false);
+        assert_eq!(unchoked(&peers), [false, true]);
+    }
+
+    #[tokio::test]
+    async fn fastest_peers_are_unchoked() {
+        let mut choker = Choker::default();
+        let peers = peers(&mut choker, UNCHOKE_SLOTS + 3).await;
+        for (i, (_, state)) in peers.iter().enumerate() {
+            state.set_interested(true);
+            state.add_downloaded(i as u64 * 100);
+        }
+        choker.run_round(false);
+        let unchoked = unchoked(&peers);
+        // the fastest, plus one of the rest
+        assert!(unchoked[3..].iter().all(|unchoked| *unchoked));
+        assert_eq!(
+            unchoked[..3].iter().filter(|unchoked| **unchoked).count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn rates_are_per_round() {
+        let mut choker = Choker::default();
+        let peers = peers(&mut choker, UNCHOKE_SLOTS + 2).await;
+        for (_, state) in &peers {
+            state.set_interested(true);
+        }
+        // a lot once, long ago, counts for nothing against a little now
+        peers[0].1.add_downloaded(1_000_000);
+        choker.run_round(false);
+        for (_, state) in &peers[1..=UNCHOKE_SLOTS] {
+            state.add_downloaded(10);
+        }
+        choker.run_round(false);
+        let unchoked = unchoked(&peers);
+        assert!(unchoked[1..=UNCHOKE_SLOTS].iter().all(|unchoked| *unchoked));
+    }
+
+    #[tokio::test]
+    async fn seeds_unchoke_by_upload() {
+        let mut choker = Choker::default();
+        let peers = peers(&mut choker, UNCHOKE_SLOTS + 2).await;
+        for (i, (_, state)) in peers.iter().enumerate() {
+            state.set_interested(true);
+            state.add_uploaded(i as u64 * 100);
+            // what they gave us doesn't matter any more
+            state.add_downloaded(1000 - i as u64 * 100);
+        }
+        choker.run_round(true);
+        let unchoked = unchoked(&peers);
+        assert!(unchoked[2..].iter().all(|unchoked| *unchoked));
+        assert_eq!(
+            unchoked[..2].iter().filter(|unchoked| **unchoked).count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn optimistic_unchoke_rotates() {
+        let mut choker = Choker::default();
+        let peers = peers(&mut choker, UNCHOKE_SLOTS + 2).await;
+        for (_, state) in &peers {
+            state.set_interested(true);
+        }
+        let optimistic = |peers: &[(WorkerId, Arc<PeerState>)]| {
+            let unchoked = unchoked(peers);
+            assert_eq!(
+                unchoked[..2].iter().filter(|unchoked| **unchoked).count(),
+                1
+            );
+            if unchoked[0] {
+                0
+            } else {
+                1
+            }
+        };
+        // the same peers stay fastest throughout
+        let round = |choker: &mut Choker| {
+            for (_, state) in &peers[2..] {
+                state.add_downloaded(100);
+            }
+            choker.run_round(false);
+        };
+        round(&mut choker);
+        let first = optimistic(&peers);
+        for _ in 1..OPTIMISTIC_ROUNDS {
+            round(&mut choker);
+            assert_eq!(optimistic(&peers), first);
+        }
+
+        // losing interest loses the slot straight away
+        peers[first].1.set_interested(false);
+        round(&mut choker);
+        assert_eq!(optimistic(&peers), 1 - first);
+
+        // as does going away
+        choker.remove(peers[1 - first].0);
+        round(&mut choker);
+        assert_eq!(choker.optimistic, None);
+    }
+}
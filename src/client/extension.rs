@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+
+// Extended message id 0 is reserved for the extension handshake, every other
+// id is assigned by the receiving side in its handshake's `m` dictionary.
+pub const HANDSHAKE_ID: u8 = 0;
+
+// How many outstanding requests we are willing to queue for a peer.
+pub(crate) const REQUEST_QUEUE_SIZE: usize = 250;
+
+// The payload of the BEP 10 extension handshake.  Every key is optional.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    // Maps extension names to the message id the sender wants to receive
+    // them as.  An id of 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    // Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    // Number of outstanding requests the sender will queue before dropping
+    // them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    // The port the sender listens on for incoming connections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    // BEP 9, size of the info dictionary in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    // The id to use when sending the named extension's messages to the peer
+    // that sent this handshake.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != HANDSHAKE_ID)
+    }
+
+    pub fn request_queue_size(&self) -> Option<usize> {
+        self.reqq.and_then(|reqq| usize::try_from(reqq).ok())
+    }
+}
+
+// An extension that can be plugged into the peer wire protocol.  Every
+// registered extension is advertised in our handshake, and the messages peers
+// send for it are handed to `handle_message`.
+pub trait ExtensionHandler: Send + Sync + fmt::Debug {
+    // The name the extension is advertised under in the `m` dictionary,
+    // eg. "ut_metadata".
+    fn name(&self) -> &'static str;
+
+    // Lets the extension add its own keys to our handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    // Handles the payload of a message the peer sent for this extension.  A
+    // returned payload is sent back to the peer under the id it assigned to
+    // the extension.
+    fn handle_message(&self, payload: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
+#[derive(Debug, Default)]
+pub struct ExtensionRegistry {
+    // The id peers use to send us an extension's messages is its index in
+    // this list plus one, since 0 is the handshake.
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    // Left out of the handshake when we aren't accepting connections.
+    listen_port: Option<u16>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) {
+        self.handlers.push(handler);
+    }
+
+    pub fn set_listen_port(&mut self, listen_port: Option<u16>) {
+        self.listen_port = listen_port;
+    }
+
+    // The handshake we send to every peer that supports the extension
+    // protocol.
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .handlers
+                .iter()
+                .enumerate()
+                .map(|(i, handler)| (String::from(handler.name()), i as i64 + 1))
+                .collect(),
+            v: Some(format!("leech {}", env!("CARGO_PKG_VERSION"))),
+            reqq: Some(REQUEST_QUEUE_SIZE as i64),
+            p: self.listen_port,
+            metadata_size: None,
+        };
+        for handler in &self.handlers {
+            handler.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    // Looks up the extension a peer's message is addressed to by the id we
+    // assigned it.
+    pub fn handler(&self, id: u8) -> Option<&dyn ExtensionHandler> {
+        (id as usize)
+            .checked_sub(1)
+            .and_then(|i| self.handlers.get(i))
+            .map(|handler| handler.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::metadata::{MetadataExtension, UT_METADATA, UT_METADATA_ID};
+    use bytes::Bytes;
+
+    fn registry() -> ExtensionRegistry {
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(MetadataExtension::new(Bytes::from_static(
+            b"d4:name4:teste",
+        ))));
+        registry.set_listen_port(Some(6881));
+        registry
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let payload = serde_bencode::to_bytes(&registry().handshake()).unwrap();
+        let handshake: ExtendedHandshake = serde_bencode::from_bytes(&payload).unwrap();
+        assert_eq!(handshake.extension_id(UT_METADATA), Some(UT_METADATA_ID));
+        assert_eq!(handshake.metadata_size, Some(14));
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.request_queue_size(), Some(REQUEST_QUEUE_SIZE));
+        assert!(handshake.v.unwrap().starts_with("leech "));
+    }
+
+    #[test]
+    fn no_port_when_not_listening() {
+        let mut registry = registry();
+        registry.set_listen_port(None);
+        let payload = serde_bencode::to_bytes(&registry.handshake()).unwrap();
+        assert!(!payload.windows(3).any(|key| key == b"1:p"));
+    }
+
+    #[test]
+    fn peer_extension_ids() {
+        let payload = b"d1:md11:ut_metadatai3e6:ut_pexi0e5:largei300ee6:yourip4:abcde";
+        let handshake: ExtendedHandshake = serde_bencode::from_bytes(payload).unwrap();
+        assert_eq!(handshake.extension_id(UT_METADATA), Some(3));
+        // disabled, out of range and never mentioned
+        assert_eq!(handshake.extension_id("ut_pex"), None);
+        assert_eq!(handshake.extension_id("large"), None);
+        assert_eq!(handshake.extension_id("lt_donthave"), None);
+        assert_eq!(handshake.request_queue_size(), None);
+    }
+
+    #[test]
+    fn messages_reach_their_handler() {
+        let registry = registry();
+        let handler = registry.handler(UT_METADATA_ID).unwrap();
+        assert_eq!(handler.name(), UT_METADATA);
+        assert!(registry.handler(HANDSHAKE_ID).is_none());
+        assert!(registry.handler(UT_METADATA_ID + 1).is_none());
+    }
+}
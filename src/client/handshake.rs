@@ -10,7 +10,7 @@ use super::types::{InfoHash, PeerId};
 pub struct Handshake {
     //The protocol string, which is the literal 'BitTorrent protocol'.
     pub pstr: [u8; 19],
-    // 8 reserved bytes, all 0s.
+    // 8 reserved bytes, each set bit advertises support for an extension.
     pub reserved: [u8; 8],
     // The torrent's SHA1 info hash, used to identify the torrent
     pub info_hash: InfoHash,
@@ -20,19 +20,31 @@ pub struct Handshake {
 
 pub const PROTOCOL_STRING: &str = "BitTorrent protocol";
 
+// BEP 10 reserves the 20th bit from the right, ie. reserved[5] & 0x10, to
+// advertise support for the extension protocol.
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
 impl Handshake {
     pub fn new(info_hash: InfoHash, peer_id: PeerId) -> Self {
         let mut pstr = [0; 19];
         pstr.copy_from_slice(PROTOCOL_STRING.as_bytes());
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
         Handshake {
             pstr,
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
 }
 
+#[derive(Debug)]
 pub struct HandshakeCodec;
 
 impl Encoder<Handshake> for HandshakeCodec {
@@ -70,7 +82,7 @@ impl Decoder for HandshakeCodec {
         // temp buffer.
         let mut temp_buf = Cursor::new(&buf);
         let pstr_len = temp_buf.get_u8() as usize;
-        if pstr_len != PROTOCOL_STRING.as_bytes().len() {
+        if pstr_len != PROTOCOL_STRING.len() {
             return Err(anyhow!(
                 "pstr_len is not equal to the length of the protocol string"
             ));
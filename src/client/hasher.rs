@@ -0,0 +1,175 @@
+use super::scheduler::WorkerId;
+use super::PieceWork;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use tokio::sync::{mpsc::Sender, Semaphore};
+
+// A downloaded piece once it has been hashed.
+#[derive(Debug)]
+pub(crate) struct PieceResult {
+    // Who downloaded it.
+    pub(crate) worker: WorkerId,
+    pub(crate) work: PieceWork,
+    pub(crate) buf: BytesMut,
+    pub(crate) verified: bool,
+}
+
+// Hashing a piece takes long enough to stall every other task on the same
+// executor thread, so it happens on tokio's blocking pool instead.  At most
+// one piece per core is hashed at a time, workers wait for a free slot
+// before handing over another piece.
+#[derive(Debug, Clone)]
+pub(crate) struct PieceHasher {
+    permits: Arc<Semaphore>,
+    results: Sender<PieceResult>,
+    stats: Arc<HashStats>,
+}
+
+impl PieceHasher {
+    pub(crate) fn new(results: Sender<PieceResult>) -> Self {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        PieceHasher {
+            permits: Arc::new(Semaphore::new(threads)),
+            results,
+            stats: Arc::new(HashStats::default()),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> &HashStats {
+        &self.stats
+    }
+
+    // Returns as soon as hashing has started, the result is sent to the
+    // session once it's done.
+    pub(crate) async fn submit(
+        &self,
+        worker: WorkerId,
+        work: PieceWork,
+        buf: BytesMut,
+    ) -> Result<()> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let (results, stats) = (self.results.clone(), self.stats.clone());
+        tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            let verified = work.check_integrity(&buf);
+            stats.record(buf.len(), started, verified);
+            drop(permit);
+            // the session has stopped listening once the download is over
+            let _ = results.blocking_send(PieceResult {
+                worker,
+                work,
+                buf,
+                verified,
+            });
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct HashStats {
+    pieces: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+    // total time spent hashing, over every thread
+    nanos: AtomicU64,
+}
+
+impl HashStats {
+    fn record(&self, bytes: usize, started: Instant, verified: bool) {
+        self.pieces.fetch_add(1, Ordering::Relaxed);
+        if !verified {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn pieces(&self) -> u64 {
+        self.pieces.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    // How fast a single thread gets through piece data, in MB/s.
+    pub(crate) fn throughput(&self) -> f64 {
+        let nanos = self.nanos.load(Ordering::Relaxed);
+        if nanos == 0 {
+            return 0.0;
+        }
+        let bytes = self.bytes.load(Ordering::Relaxed) as f64;
+        bytes / 1_000_000.0 / (nanos as f64 / 1_000_000_000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::torrent::test_helpers::workers;
+    use crate::client::torrent::TorrentFile;
+    use tokio::sync::mpsc;
+
+    fn piece() -> PieceWork {
+        let torrent = TorrentFile::for_tests(16, &[&[1; 16]]);
+        PieceWork::all(&torrent)[0]
+    }
+
+    #[tokio::test]
+    async fn pieces_are_checked() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let hasher = PieceHasher::new(tx);
+        let worker = workers(1).await[0];
+        hasher
+            .submit(worker, piece(), BytesMut::from(&[1; 16][..]))
+            .await
+            .unwrap();
+        hasher
+            .submit(worker, piece(), BytesMut::from(&[2; 16][..]))
+            .await
+            .unwrap();
+
+        let mut verified = vec![];
+        for _ in 0..2 {
+            let result = rx.recv().await.unwrap();
+            assert_eq!(result.worker, worker);
+            assert_eq!(result.work.index, 0);
+            verified.push((result.buf[0], result.verified));
+        }
+        verified.sort();
+        assert_eq!(verified, [(1, true), (2, false)]);
+        assert_eq!(hasher.stats().pieces(), 2);
+        assert_eq!(hasher.stats().failed(), 1);
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_free_thread() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let hasher = PieceHasher::new(tx);
+        let threads = hasher.permits.available_permits();
+        // every hashing thread is busy
+        let busy = hasher
+            .permits
+            .clone()
+            .acquire_many_owned(threads as u32)
+            .await
+            .unwrap();
+
+        let worker = workers(1).await[0];
+        let submit = hasher.submit(worker, piece(), BytesMut::from(&[1; 16][..]));
+        tokio::pin!(submit);
+        assert!(futures::poll!(&mut submit).is_pending());
+
+        drop(busy);
+        submit.await.unwrap();
+        assert!(rx.recv().await.unwrap().verified);
+        assert_eq!(hasher.permits.available_permits(), threads);
+    }
+}
@@ -0,0 +1,200 @@
+use super::handshake::{Handshake, HandshakeCodec};
+use super::peer::Peer;
+use super::peerclient::HANDSHAKE_TIMEOUT;
+use super::types::InfoHash;
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+// A peer that dialled us, with its handshake read but not yet answered.
+#[derive(Debug)]
+pub(crate) struct InboundPeer {
+    pub(crate) peer: Peer,
+    pub(crate) socket: Framed<TcpStream, HandshakeCodec>,
+    pub(crate) handshake: Handshake,
+}
+
+// Accepts connections from peers and hands each one to the session for the
+// torrent named in its handshake.  Several sessions can share a listener.
+#[derive(Debug)]
+pub struct PeerListener {
+    port: u16,
+    // How long a peer gets to send its handshake once connected.
+    handshake_timeout: Duration,
+    torrents: Mutex<HashMap<InfoHash, UnboundedSender<InboundPeer>>>,
+}
+
+impl PeerListener {
+    // Listens on `port` on every interface, 0 picks a free one.  Stops
+    // accepting once the last reference is dropped.
+    pub async fn bind(port: u16) -> Result<Arc<Self>> {
+        Self::bind_with_timeout(port, HANDSHAKE_TIMEOUT).await
+    }
+
+    // Like `bind`, but gives peers `handshake_timeout` to send their
+    // handshake.
+    pub async fn bind_with_timeout(port: u16, handshake_timeout: Duration) -> Result<Arc<Self>> {
+        // Where IPv6 sockets accept IPv4 connections too, which is the default
+        // on Linux, the IPv4 bind fails and the IPv6 socket covers both.
+        let mut listeners = Vec::new();
+        let mut port = port;
+        match TcpListener::bind(("::", port)).await {
+            Ok(listener) => {
+                port = listener.local_addr()?.port();
+                listeners.push(listener);
+            }
+            Err(e) => println!("not accepting peers over IPv6: {:?}", e),
+        }
+        match TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => {
+                port = listener.local_addr()?.port();
+                listeners.push(listener);
+            }
+            Err(e) if listeners.is_empty() => return Err(e.into()),
+            Err(_) => {}
+        }
+        let peer_listener = Arc::new(PeerListener {
+            port,
+            handshake_timeout,
+            torrents: Mutex::new(HashMap::new()),
+        });
+        for listener in listeners {
+            tokio::spawn(accept(listener, Arc::downgrade(&peer_listener)));
+        }
+        Ok(peer_listener)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Peers asking for `info_hash` are sent to the returned receiver until
+    // it's unregistered.
+    pub(crate) fn register(&self, info_hash: InfoHash) -> UnboundedReceiver<InboundPeer> {
+        let (tx, rx) = unbounded_channel();
+        self.torrents.lock().unwrap().insert(info_hash, tx);
+        rx
+    }
+
+    pub(crate) fn unregister(&self, info_hash: InfoHash) {
+        self.torrents.lock().unwrap().remove(&info_hash);
+    }
+
+    async fn route(&self, stream: TcpStream, peer: Peer) -> Result<()> {
+        let mut socket = Framed::new(stream, HandshakeCodec);
+        let handshake = timeout(self.handshake_timeout, socket.next())
+            .await
+            .map_err(|_| anyhow!("timed out waiting for handshake"))?
+            .ok_or_else(|| anyhow!("connection closed during handshake"))??;
+        let session = self
+            .torrents
+            .lock()
+            .unwrap()
+            .get(&handshake.info_hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown info hash {:?}", handshake.info_hash))?;
+        session
+            .send(InboundPeer {
+                peer,
+                socket,
+                handshake,
+            })
+            .map_err(|_| anyhow!("session is shutting down"))
+    }
+}
+
+async fn accept(listener: TcpListener, peer_listener: Weak<PeerListener>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("unable to accept peer: {:?}", e);
+                continue;
+            }
+        };
+        let peer_listener = match peer_listener.upgrade() {
+            Some(peer_listener) => peer_listener,
+            None => return,
+        };
+        // IPv4 peers reaching the IPv6 socket show up as v4-mapped addresses
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        // reading a handshake can take a while, don't hold up the next peer
+        tokio::spawn(async move {
+            let peer = Peer::from(addr);
+            if let Err(e) = peer_listener.route(stream, peer).await {
+                println!("rejected peer {}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use std::net::IpAddr;
+
+    // Dials the listener and sends a handshake for `info_hash`.
+    async fn dial(
+        listener: &PeerListener,
+        info_hash: InfoHash,
+    ) -> Framed<TcpStream, HandshakeCodec> {
+        let stream = TcpStream::connect(("127.0.0.1", listener.port()))
+            .await
+            .unwrap();
+        let mut socket = Framed::new(stream, HandshakeCodec);
+        socket
+            .send(Handshake::new(info_hash, [2; 20]))
+            .await
+            .unwrap();
+        socket
+    }
+
+    // The listener hangs up on peers it has nowhere to send.
+    async fn assert_dropped(mut socket: Framed<TcpStream, HandshakeCodec>) {
+        let next = timeout(Duration::from_secs(5), socket.next())
+            .await
+            .unwrap();
+        assert!(next.is_none());
+    }
+
+    #[tokio::test]
+    async fn peers_reach_their_session() {
+        let listener = PeerListener::bind(0).await.unwrap();
+        let mut first = listener.register([1; 20]);
+        let mut second = listener.register([2; 20]);
+
+        let _socket = dial(&listener, [2; 20]).await;
+        let inbound = second.recv().await.unwrap();
+        assert_eq!(inbound.handshake.info_hash, [2; 20]);
+        assert_eq!(inbound.handshake.peer_id, [2; 20]);
+        assert_eq!(inbound.peer.addr, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert!(first.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_info_hash_is_dropped() {
+        let listener = PeerListener::bind(0).await.unwrap();
+        let _session = listener.register([1; 20]);
+        assert_dropped(dial(&listener, [9; 20]).await).await;
+
+        listener.register([3; 20]);
+        listener.unregister([3; 20]);
+        assert_dropped(dial(&listener, [3; 20]).await).await;
+    }
+
+    #[tokio::test]
+    async fn closed_session_is_dropped() {
+        let listener = PeerListener::bind(0).await.unwrap();
+        drop(listener.register([1; 20]));
+        assert_dropped(dial(&listener, [1; 20]).await).await;
+    }
+}
@@ -0,0 +1,196 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use super::types::InfoHash;
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+
+// A parsed magnet URI.  Only the info hash is required, everything else is a
+// hint about where to find peers or what to call the download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    // xt, exact topic
+    pub info_hash: InfoHash,
+    // dn, display name
+    pub display_name: Option<String>,
+    // tr, tracker urls
+    pub trackers: Vec<String>,
+    // x.pe, peer addresses as `host:port`
+    pub peers: Vec<String>,
+    // ws, web seeds
+    pub web_seeds: Vec<String>,
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let query = uri
+            .strip_prefix(MAGNET_PREFIX)
+            .ok_or_else(|| anyhow!("not a magnet uri: {}", uri))?;
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query)?;
+
+        let mut info_hash = None;
+        let mut magnet = Magnet {
+            info_hash: [0; 20],
+            display_name: None,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+            web_seeds: Vec::new(),
+        };
+        for (key, value) in params {
+            // Some clients number repeated parameters, eg. `tr.1`, `tr.2`.
+            let key = match key.split_once('.') {
+                Some((base, n)) if n.bytes().all(|c| c.is_ascii_digit()) => base,
+                _ => &key,
+            };
+            match key {
+                "xt" => {
+                    // Other topics (btmh for v2 torrents, ed2k, ...) can be
+                    // present alongside the one we understand.
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(decode_info_hash(hash)?);
+                    }
+                }
+                "dn" => magnet.display_name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                "x.pe" => magnet.peers.push(value),
+                _ => {}
+            }
+        }
+        magnet.info_hash =
+            info_hash.ok_or_else(|| anyhow!("magnet uri has no urn:btih exact topic"))?;
+        Ok(magnet)
+    }
+}
+
+// Info hashes are either 40 hex characters or 32 base32 characters.
+fn decode_info_hash(hash: &str) -> Result<InfoHash> {
+    match hash.len() {
+        40 => decode_hex(hash),
+        32 => decode_base32(hash),
+        len => Err(anyhow!("info hash has invalid length {}", len)),
+    }
+}
+
+fn decode_hex(hash: &str) -> Result<InfoHash> {
+    // the length was counted in bytes, slicing a multi-byte character in
+    // half would panic, and from_str_radix would take a leading `+`
+    if !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("invalid hex info hash {}", hash));
+    }
+    let mut info_hash = [0; 20];
+    for (i, byte) in info_hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("invalid hex info hash {}", hash))?;
+    }
+    Ok(info_hash)
+}
+
+// RFC 4648 base32, 32 characters of 5 bits each make exactly 160 bits.
+fn decode_base32(hash: &str) -> Result<InfoHash> {
+    let mut info_hash = [0; 20];
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    let mut i = 0;
+    for c in hash.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return Err(anyhow!("invalid base32 info hash {}", hash)),
+        };
+        bits = ((bits << 5) | value as u32) & 0xffff;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            info_hash[i] = (bits >> bit_count) as u8;
+            i += 1;
+        }
+    }
+    Ok(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+    const BASE32: &str = "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+    const INFO_HASH: InfoHash = [
+        0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9, 0xf5, 0x19, 0xb3, 0x35, 0xaa,
+        0x7c, 0x13, 0x67, 0xa8, 0x8a,
+    ];
+
+    #[test]
+    fn hex_info_hash() {
+        let magnet: Magnet = format!("magnet:?xt=urn:btih:{}", HEX).parse().unwrap();
+        assert_eq!(magnet.info_hash, INFO_HASH);
+        let upper: Magnet = format!("magnet:?xt=urn:btih:{}", HEX.to_uppercase())
+            .parse()
+            .unwrap();
+        assert_eq!(upper.info_hash, INFO_HASH);
+    }
+
+    #[test]
+    fn base32_info_hash() {
+        let magnet: Magnet = format!("magnet:?xt=urn:btih:{}", BASE32).parse().unwrap();
+        assert_eq!(magnet.info_hash, INFO_HASH);
+        let lower: Magnet = format!("magnet:?xt=urn:btih:{}", BASE32.to_lowercase())
+            .parse()
+            .unwrap();
+        assert_eq!(lower.info_hash, INFO_HASH);
+    }
+
+    #[test]
+    fn every_parameter() {
+        let uri = format!(
+            "magnet:?xt=urn:btmh:1220abcd&xt=urn:btih:{}&dn=some+name\
+             &tr.1=http%3A%2F%2Fa%2Fannounce&tr.2=udp%3A%2F%2Fb%3A80\
+             &x.pe=10.0.0.1%3A6881&ws=http%3A%2F%2Fseed&unknown=1",
+            HEX
+        );
+        let magnet: Magnet = uri.parse().unwrap();
+        assert_eq!(
+            magnet,
+            Magnet {
+                info_hash: INFO_HASH,
+                display_name: Some(String::from("some name")),
+                trackers: vec![
+                    String::from("http://a/announce"),
+                    String::from("udp://b:80")
+                ],
+                peers: vec![String::from("10.0.0.1:6881")],
+                web_seeds: vec![String::from("http://seed")],
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_uris() {
+        assert!("http://example.com".parse::<Magnet>().is_err());
+        assert!("magnet:?dn=name".parse::<Magnet>().is_err());
+        assert!("magnet:?xt=urn:btih:abcd".parse::<Magnet>().is_err());
+        // right length, wrong alphabet
+        let bad_hex = HEX.replace('c', "g");
+        assert!(format!("magnet:?xt=urn:btih:{}", bad_hex)
+            .parse::<Magnet>()
+            .is_err());
+        let signed = format!("magnet:?xt=urn:btih:%2B{}", &HEX[1..]);
+        assert!(signed.parse::<Magnet>().is_err());
+        let bad_base32 = BASE32.replace('Y', "1");
+        assert!(format!("magnet:?xt=urn:btih:{}", bad_base32)
+            .parse::<Magnet>()
+            .is_err());
+    }
+
+    #[test]
+    fn non_ascii_hex_is_an_error() {
+        // 40 bytes, but a character straddles the two digit boundary
+        let hash = format!("{}\u{e9}{}", &HEX[..1], &HEX[3..]);
+        assert_eq!(hash.len(), 40);
+        assert!(decode_info_hash(&hash).is_err());
+    }
+}
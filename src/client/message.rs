@@ -5,7 +5,7 @@ use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
 use super::block::BlockInfo;
-use super::types::{Bitfield, PieceHash};
+use super::types::Bitfield;
 
 #[derive(Debug)]
 #[repr(u8)]
@@ -27,6 +27,9 @@ pub enum MessageId {
     Request = 6,
     Block = 7,
     Cancel = 8,
+    // BEP 10 extension protocol messages, the first byte of the payload is
+    // the extended message id.
+    Extended = 20,
 }
 
 impl TryFrom<u8> for MessageId {
@@ -44,6 +47,7 @@ impl TryFrom<u8> for MessageId {
             i if i == Request as u8 => Ok(MessageId::Request),
             i if i == Block as u8 => Ok(MessageId::Block),
             i if i == Cancel as u8 => Ok(MessageId::Cancel),
+            i if i == Extended as u8 => Ok(MessageId::Extended),
             _ => Err(anyhow!("Invalid message id")),
         }
     }
@@ -67,8 +71,13 @@ pub enum Message {
         block_data: Vec<u8>,
     },
     Cancel(BlockInfo),
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
+#[derive(Debug)]
 pub struct PeerCodec;
 
 impl Encoder<Message> for PeerCodec {
@@ -83,7 +92,9 @@ impl Encoder<Message> for PeerCodec {
                 // no payload
             }
             Bitfield(bitfield) => {
-                let msg_len = 1 + bitfield.len() / 8;
+                // the last byte is padded out when the piece count isn't a
+                // multiple of eight
+                let msg_len = 1 + bitfield.as_raw_slice().len();
                 buf.put_u32(msg_len as u32);
                 buf.put_u8(MessageId::Bitfield as u8);
                 buf.extend_from_slice(bitfield.as_raw_slice());
@@ -141,6 +152,13 @@ impl Encoder<Message> for PeerCodec {
                 buf.put_u8(MessageId::Cancel as u8);
                 block_info.encode(buf)?;
             }
+            Extended { id, payload } => {
+                let msg_len = 2 + payload.len() as u32;
+                buf.put_u32(msg_len);
+                buf.put_u8(MessageId::Extended as u8);
+                buf.put_u8(id);
+                buf.put(&payload[..]);
+            }
         }
         Ok(())
     }
@@ -222,6 +240,17 @@ impl Decoder for PeerCodec {
                     block_length,
                 })
             }
+            MessageId::Extended => {
+                // the extended id is the least a peer can send
+                if msg_len < 2 {
+                    return Err(anyhow!("extended message too short"));
+                }
+                let id = buf.get_u8();
+                // everything except the message and extended ids
+                let mut payload = vec![0; msg_len - 2];
+                buf.copy_to_slice(&mut payload);
+                Message::Extended { id, payload }
+            }
         };
 
         Ok(Some(msg))
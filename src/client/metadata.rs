@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::{stream, SinkExt, StreamExt};
+use serde_derive::{Deserialize, Serialize};
+use sha1::Sha1;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+use super::bencode;
+use super::extension::{ExtendedHandshake, ExtensionHandler, HANDSHAKE_ID};
+use super::handshake::{Handshake, HandshakeCodec};
+use super::message::Message;
+use super::peer::Peer;
+use super::peerclient::into_peer_codec;
+use super::types::{InfoHash, PeerId};
+
+pub const UT_METADATA: &str = "ut_metadata";
+// The id we ask peers to use for the ut_metadata messages they send us.
+pub const UT_METADATA_ID: u8 = 1;
+
+// Metadata is exchanged in 16 KiB pieces, the last one may be shorter.
+const METADATA_PIECE_SIZE: usize = 16384;
+// Refuse to allocate for absurd sizes advertised by a peer, real info
+// dictionaries are rarely more than a few megabytes.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+// How many peers to ask at once, and how long to give each one.
+const MAX_CONCURRENT_FETCHES: usize = 5;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+enum MetadataMessageType {
+    Request = 0,
+    Data = 1,
+    Reject = 2,
+}
+
+// The bencoded dictionary at the start of every ut_metadata message.  Data
+// messages have the piece's bytes appended directly after the dictionary.
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+// Splits a ut_metadata message into its dictionary and any trailing data.
+fn decode_metadata_message(payload: &[u8]) -> Result<(MetadataMessage, &[u8])> {
+    let dict_len = bencode::value_len(payload)?;
+    let message = serde_bencode::from_bytes::<MetadataMessage>(&payload[..dict_len])?;
+    Ok((message, &payload[dict_len..]))
+}
+
+// Serves our copy of the info dictionary to peers that ask for it.
+#[derive(Debug)]
+pub struct MetadataExtension {
+    info: Bytes,
+}
+
+impl MetadataExtension {
+    pub fn new(info: Bytes) -> Self {
+        Self { info }
+    }
+}
+
+impl ExtensionHandler for MetadataExtension {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = Some(self.info.len() as i64);
+    }
+
+    fn handle_message(&self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (message, _) = decode_metadata_message(payload)?;
+        if message.msg_type != MetadataMessageType::Request as u8 {
+            return Ok(None);
+        }
+
+        // the piece number is the peer's to choose, however large
+        let start = message.piece.checked_mul(METADATA_PIECE_SIZE);
+        let start = match start.filter(|start| *start < self.info.len()) {
+            Some(start) => start,
+            None => {
+                let reject = MetadataMessage {
+                    msg_type: MetadataMessageType::Reject as u8,
+                    piece: message.piece,
+                    total_size: None,
+                };
+                return Ok(Some(serde_bencode::to_bytes(&reject)?));
+            }
+        };
+        let end = std::cmp::min(start + METADATA_PIECE_SIZE, self.info.len());
+        let data = MetadataMessage {
+            msg_type: MetadataMessageType::Data as u8,
+            piece: message.piece,
+            total_size: Some(self.info.len()),
+        };
+        let mut reply = serde_bencode::to_bytes(&data)?;
+        reply.extend_from_slice(&self.info[start..end]);
+        Ok(Some(reply))
+    }
+}
+
+// Downloads the info dictionary for `info_hash` from whichever of `peers`
+// provides a copy matching the hash first (BEP 9).
+pub async fn fetch_metadata(peers: &[Peer], info_hash: InfoHash, peer_id: PeerId) -> Result<Bytes> {
+    let mut attempts = stream::iter(peers)
+        .map(|peer| async move {
+            let result = timeout(FETCH_TIMEOUT, fetch_from_peer(peer, info_hash, peer_id)).await;
+            (peer, result)
+        })
+        .buffer_unordered(MAX_CONCURRENT_FETCHES);
+
+    while let Some((peer, result)) = attempts.next().await {
+        match result {
+            Ok(Ok(metadata)) => return Ok(metadata),
+            Ok(Err(e)) => println!("metadata fetch from peer {} failed: {:?}", peer, e),
+            Err(_) => println!("metadata fetch from peer {} timed out", peer),
+        }
+    }
+    Err(anyhow!("no peer was able to provide the metadata"))
+}
+
+async fn fetch_from_peer(peer: &Peer, info_hash: InfoHash, peer_id: PeerId) -> Result<Bytes> {
+    let connection = TcpStream::connect(peer.socket_addr).await?;
+    let mut socket = Framed::new(connection, HandshakeCodec);
+    socket.send(Handshake::new(info_hash, peer_id)).await?;
+    let peer_handshake = socket
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("connection closed during handshake"))??;
+    if peer_handshake.info_hash != info_hash {
+        return Err(anyhow!("peer responded with a different info hash"));
+    }
+    if !peer_handshake.supports_extension_protocol() {
+        return Err(anyhow!("peer does not support the extension protocol"));
+    }
+
+    let mut socket = into_peer_codec(socket);
+
+    let handshake = ExtendedHandshake {
+        m: BTreeMap::from([(String::from(UT_METADATA), UT_METADATA_ID as i64)]),
+        ..Default::default()
+    };
+    socket
+        .send(Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: serde_bencode::to_bytes(&handshake)?,
+        })
+        .await?;
+
+    let peer_extensions = loop {
+        match socket.next().await {
+            Some(Ok(Message::Extended {
+                id: HANDSHAKE_ID,
+                payload,
+            })) => break serde_bencode::from_bytes::<ExtendedHandshake>(&payload)?,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e),
+            None => return Err(anyhow!("connection closed before extension handshake")),
+        }
+    };
+    let ut_metadata = peer_extensions
+        .extension_id(UT_METADATA)
+        .ok_or_else(|| anyhow!("peer does not support {}", UT_METADATA))?;
+    let metadata_size = peer_extensions
+        .metadata_size
+        .and_then(|size| usize::try_from(size).ok())
+        .filter(|size| *size > 0 && *size <= MAX_METADATA_SIZE)
+        .ok_or_else(|| anyhow!("peer sent missing or invalid metadata_size"))?;
+
+    let piece_count = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..piece_count {
+        let request = MetadataMessage {
+            msg_type: MetadataMessageType::Request as u8,
+            piece,
+            total_size: None,
+        };
+        socket
+            .send(Message::Extended {
+                id: ut_metadata,
+                payload: serde_bencode::to_bytes(&request)?,
+            })
+            .await?;
+    }
+
+    let mut metadata = vec![0; metadata_size];
+    let mut received = vec![false; piece_count];
+    while received.iter().any(|r| !r) {
+        let payload = match socket.next().await {
+            Some(Ok(Message::Extended {
+                id: UT_METADATA_ID,
+                payload,
+            })) => payload,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e),
+            None => return Err(anyhow!("connection closed during metadata exchange")),
+        };
+        let (message, data) = decode_metadata_message(&payload)?;
+        if message.msg_type == MetadataMessageType::Reject as u8 {
+            return Err(anyhow!("peer rejected request for piece {}", message.piece));
+        }
+        if message.msg_type != MetadataMessageType::Data as u8 || message.piece >= piece_count {
+            continue;
+        }
+
+        let start = message.piece * METADATA_PIECE_SIZE;
+        let end = std::cmp::min(start + METADATA_PIECE_SIZE, metadata_size);
+        if data.len() != end - start {
+            return Err(anyhow!(
+                "metadata piece {} has length {}, expected {}",
+                message.piece,
+                data.len(),
+                end - start
+            ));
+        }
+        metadata[start..end].copy_from_slice(data);
+        received[message.piece] = true;
+    }
+
+    if Sha1::from(&metadata).digest().bytes() != info_hash {
+        return Err(anyhow!("metadata does not match the info hash"));
+    }
+    Ok(Bytes::from(metadata))
+}
@@ -1,311 +1,930 @@
+mod announcer;
+mod bencode;
 mod block;
+mod choker;
+mod extension;
 mod handshake;
+mod hasher;
+pub mod listener;
+pub mod magnet;
 mod message;
+mod metadata;
+mod partfile;
 mod peer;
 mod peerclient;
-mod torrent;
-mod tracker;
+mod piece_picker;
+pub mod priority;
+mod resume;
+mod scheduler;
+mod stats;
+pub mod storage;
+pub mod torrent;
+pub mod tracker;
 mod types;
+pub mod udp_tracker;
+mod upload;
+pub mod verify;
 
+use announcer::Announcer;
 use block::BlockInfo;
+use choker::{Choker, PeerState, CHOKE_INTERVAL};
+use extension::ExtensionRegistry;
+use hasher::{PieceHasher, PieceResult};
+use listener::{InboundPeer, PeerListener};
+use magnet::Magnet;
 use message::Message;
+use metadata::MetadataExtension;
 use peer::Peer;
-use peerclient::PeerClient;
+use peerclient::{PeerClient, PeerTimeouts};
+use piece_picker::PiecePicker;
+use priority::FilePriorities;
+use scheduler::{Scheduler, WorkerEvent, WorkerId};
+use stats::TransferStats;
+use storage::Storage;
 use torrent::TorrentFile;
-use tracker::{TrackerRequest, TrackerResponse};
-use types::{InfoHash, PeerAddr, PeerId, Peers};
-
-use std::{convert::TryInto, sync::Arc};
+use tracker::{AnnounceList, Event, TrackerRequest};
+use types::{Bitfield, InfoHash, PeerId, Peers, PieceIndex};
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::BytesMut;
+use futures::future::OptionFuture;
 use rand::Rng;
-use serde_bencode::de;
-use sha1::{Digest, Sha1};
+use sha1::Sha1;
 use tokio::sync::{
-    broadcast,
-    mpsc::{unbounded_channel, UnboundedSender},
-    Mutex,
+    mpsc::{channel, unbounded_channel, UnboundedSender},
+    oneshot, watch,
 };
+use tokio::task::{self, JoinSet};
 
 #[derive(Debug)]
 pub struct LeechClient {
     peers: Peers,
-    filename: String,
     pub torrent_file: TorrentFile,
     info_hash: InfoHash,
     peer_id: PeerId,
-    poll_interval: u32,
+    trackers: AnnounceList,
+    // Shared with the workers once we know which port we're listening on.
+    extensions: ExtensionRegistry,
+    stats: Arc<TransferStats>,
+    // Sent with every announce so trackers can tell it's still us.
+    key: u32,
+    storage: Arc<dyn Storage>,
+    // Pieces that are verified and stored.
+    own_pieces: Bitfield,
+    priorities: Arc<FilePriorities>,
+    seed_limits: SeedLimits,
+    timeouts: PeerTimeouts,
+    // Where peers that dial us come in, bound when the download starts if
+    // we haven't been given one.
+    listener: Option<Arc<PeerListener>>,
+}
+
+// How long to keep uploading once the download is done, we stop at whichever
+// limit is reached first.  With neither set we stop straight away.
+#[derive(Debug, Default, Clone, Copy)]
+struct SeedLimits {
+    // uploaded bytes over the size of the torrent
+    ratio: Option<f64>,
+    time: Option<Duration>,
+}
+
+impl SeedLimits {
+    fn reached(&self, stats: &TransferStats, length: usize, completed_at: Instant) -> bool {
+        if self.ratio.is_none() && self.time.is_none() {
+            return true;
+        }
+        let ratio = stats.uploaded() as f64 / length as f64;
+        self.ratio.is_some_and(|limit| ratio >= limit)
+            || self
+                .time
+                .is_some_and(|limit| completed_at.elapsed() >= limit)
+    }
+}
+
+// How a worker gets hold of its peer.
+#[derive(Debug)]
+enum PeerSource {
+    Dial(Peer),
+    Accepted(InboundPeer),
+}
+
+impl PeerSource {
+    fn peer(&self) -> Peer {
+        match self {
+            PeerSource::Dial(peer) => *peer,
+            PeerSource::Accepted(inbound) => inbound.peer,
+        }
+    }
+}
+
+// What every download worker shares with the session.
+#[derive(Debug, Clone)]
+struct WorkerContext {
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    extensions: Arc<ExtensionRegistry>,
+    stats: Arc<TransferStats>,
+    hasher: PieceHasher,
+    // Where workers ask the session's scheduler for work.
+    events: UnboundedSender<WorkerEvent>,
+    storage: Arc<dyn Storage>,
+    // Every piece of the torrent, by index.
+    pieces: Arc<Vec<PieceWork>>,
+    // Pieces that are verified and stored, as of the latest one.
+    own_pieces: watch::Receiver<Bitfield>,
+    // Whether everything we want is downloaded.  Goes back to false when a
+    // file is un-skipped, and workers that had stopped downloading start
+    // again.
+    complete: watch::Receiver<bool>,
+    // Pieces we don't have and whose files aren't skipped.  We're interested
+    // in a peer for as long as it has one of them.
+    wanted: watch::Receiver<Bitfield>,
+    // This worker's peer, as the choker sees it.
+    peer: Arc<PeerState>,
+    timeouts: PeerTimeouts,
 }
 
 #[derive(Debug)]
 struct PieceInProgress {
-    index: usize,
+    work: PieceWork,
     buffer: BytesMut,
     downloaded: usize,
+    // how much of the piece has been asked for
     requested: usize,
-    backlog: usize,
+}
+
+impl PieceInProgress {
+    fn new(work: PieceWork) -> Self {
+        PieceInProgress {
+            work,
+            buffer: BytesMut::from(&vec![0_u8; work.length][..]),
+            downloaded: 0,
+            requested: 0,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 struct PieceWork {
-    index: usize,
+    index: PieceIndex,
     hash: [u8; 20],
     length: usize,
 }
 
 impl PieceWork {
-    fn check_integrity(&self, buffer: &BytesMut) -> bool {
-        let hash = Sha1::from(buffer.as_ref()).digest().bytes();
-        hash.as_slice() == self.hash
+    fn all(torrent_file: &TorrentFile) -> Vec<PieceWork> {
+        torrent_file
+            .piece_hashes
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| PieceWork {
+                index,
+                hash: *hash,
+                length: torrent_file.calculate_piece_size(index),
+            })
+            .collect()
     }
-}
 
-#[derive(Debug)]
-struct PieceResult {
-    index: usize,
-    buf: BytesMut,
+    fn check_integrity(&self, buffer: &[u8]) -> bool {
+        let hash = Sha1::from(buffer).digest().bytes();
+        hash.as_slice() == self.hash
+    }
 }
 
 const MAX_BACKLOG: usize = 10;
 const MAX_REQUEST_SIZE: usize = 16384;
+const LISTEN_PORT: u16 = 6881;
+const MAX_PEERS: usize = 50;
+// Verified pieces waiting to be written to disk.  Workers wait for room
+// rather than piling up pieces in memory when the disk can't keep up.
+const MAX_PENDING_WRITES: usize = 16;
+// How often a seeding session checks whether it has reached its limits.
+const SEED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
 
 impl LeechClient {
-    pub async fn new(filename: &str) -> Result<Self> {
-        let torrent_file = TorrentFile::new(filename);
+    pub async fn new(filename: &str, storage: Arc<dyn Storage>) -> Result<Self> {
+        let torrent_file = TorrentFile::from_path(filename)?;
+        LeechClient::from_torrent_file(torrent_file, generate_peer_id(), storage, Peers::new())
+            .await
+    }
+
+    // Starts from a magnet link instead of a torrent file.  Peers are found
+    // through the link's trackers and peer addresses, and the info dictionary
+    // is fetched from them before the download proper can begin.
+    pub async fn from_magnet(uri: &str, storage: Arc<dyn Storage>) -> Result<Self> {
+        let magnet: Magnet = uri.parse()?;
+        let peer_id = generate_peer_id();
+
+        let mut peers = Peers::new();
+        for addr in &magnet.peers {
+            match tokio::net::lookup_host(addr).await {
+                Ok(addrs) => peers.extend(addrs.map(Peer::from)),
+                Err(e) => println!("unable to resolve peer {}: {:?}", addr, e),
+            }
+        }
+        // Like libtorrent, each of the magnet's trackers gets a tier of its
+        // own.
+        let tiers: Vec<Vec<String>> = magnet.trackers.iter().map(|tr| vec![tr.clone()]).collect();
+        if !tiers.is_empty() {
+            // The torrent's size isn't known until we have the metadata, any
+            // non-zero `left` keeps the tracker from treating us as a seed.
+            let req = TrackerRequest::new(magnet.info_hash, peer_id, 1);
+            for result in AnnounceList::new(&tiers).announce(&req).await {
+                match result {
+                    Ok(res) => peers.extend(res.to_peers()),
+                    Err(e) => println!("announce failed: {}", e),
+                }
+            }
+        }
+
+        let info = metadata::fetch_metadata(&peers, magnet.info_hash, peer_id).await?;
+        let torrent_file = TorrentFile::from_info_bytes(&info, tiers)?;
+        // the peers that gave us the metadata are the first to download from
+        LeechClient::from_torrent_file(torrent_file, peer_id, storage, peers).await
+    }
+
+    async fn from_torrent_file(
+        torrent_file: TorrentFile,
+        peer_id: PeerId,
+        storage: Arc<dyn Storage>,
+        peers: Peers,
+    ) -> Result<Self> {
+        storage.open(&torrent_file)?;
+        let own_pieces = resume::own_pieces(&torrent_file, &storage).await?;
+        let have: usize = own_pieces
+            .iter_ones()
+            .map(|index| torrent_file.calculate_piece_size(index))
+            .sum();
+        if own_pieces.any() {
+            println!(
+                "resuming with {} of {} pieces",
+                own_pieces.count_ones(),
+                torrent_file.piece_count
+            );
+        }
+        let mut extensions = ExtensionRegistry::new();
+        extensions.register(Box::new(MetadataExtension::new(
+            torrent_file.info.raw.clone(),
+        )));
         let mut client = LeechClient {
-            filename: String::from(filename),
             info_hash: torrent_file.info.info_hash,
+            stats: Arc::new(TransferStats::new((torrent_file.info.length - have) as u64)),
+            trackers: AnnounceList::new(&torrent_file.announce_list),
+            priorities: Arc::new(FilePriorities::new(&torrent_file)),
             torrent_file,
             peers: Vec::<Peer>::new(),
-            peer_id: generate_peer_id(),
-            poll_interval: 0,
+            peer_id,
+            extensions,
+            key: rand::thread_rng().gen(),
+            storage,
+            own_pieces,
+            seed_limits: SeedLimits::default(),
+            timeouts: PeerTimeouts::default(),
+            listener: None,
         };
-        client.poll_tracker().await?;
+        client.add_peers(peers);
         Ok(client)
     }
 
-    fn set_peers(&mut self, peer_blob: Bytes) {
-        let peer_addrs: Vec<PeerAddr> =
-            peer_blob.chunks(6).map(|p| p.try_into().unwrap()).collect();
-        self.peers = peer_addrs.iter().map(|addr| Peer::from(*addr)).collect();
+    // Adds peers we don't already know about, the same peer is often handed
+    // out by more than one tracker.
+    fn add_peers(&mut self, peers: Peers) {
+        let mut known: HashSet<_> = self.peers.iter().map(|peer| peer.socket_addr).collect();
+        for peer in peers {
+            if known.insert(peer.socket_addr) {
+                self.peers.push(peer);
+            }
+        }
+    }
+
+    // Keeps uploading once the download is done, until we've sent `ratio`
+    // times the size of the torrent.
+    pub fn with_seed_ratio(mut self, ratio: f64) -> Self {
+        self.seed_limits.ratio = Some(ratio);
+        self
+    }
+
+    // Keeps uploading for `time` once the download is done.
+    pub fn with_seed_time(mut self, time: Duration) -> Self {
+        self.seed_limits.time = Some(time);
+        self
+    }
+
+    // Gives up on peers that don't accept our connection within `timeout`.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.connect = timeout;
+        self
     }
 
+    // Gives up on peers that haven't finished the handshake within `timeout`
+    // of connecting.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+
+    // Drops peers that send nothing, not even a keep-alive, for `timeout`.
+    // Whatever they were downloading goes to someone else.
+    pub fn with_inactivity_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.inactivity = timeout;
+        self
+    }
+
+    // Accepts peers through `listener` rather than binding our own, so
+    // several torrents can share a port.  Peers dialling in get the
+    // listener's handshake timeout, not ours.
+    pub fn with_listener(mut self, listener: Arc<PeerListener>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    // Shared with the session, so priorities can still be changed once the
+    // download has started.
+    pub fn file_priorities(&self) -> Arc<FilePriorities> {
+        self.priorities.clone()
+    }
+
+    // Deals with one message from the peer.  Blocks go into the piece being
+    // downloaded, which is handed off for hashing once it's complete.
     async fn handle_message(
-        piece_progress: &mut PieceInProgress,
+        message: Message,
+        download: &mut Option<PieceInProgress>,
         client: &mut PeerClient,
+        context: &WorkerContext,
     ) -> Result<()> {
-        let message = client.handle_message().await?; // blocks?
+        let worker = task::id();
         match message {
-            Message::Unchoke => client.choked = false,
-            Message::Choke => client.choked = true,
-            Message::Have { piece_index } => client.bitfield.set(piece_index, true),
+            Message::Unchoke => client.peer_choking = false,
+            Message::Choke => {
+                client.peer_choking = true;
+                // peers drop our outstanding requests when they choke us, so
+                // the piece is better off with someone else
+                client.outstanding.clear();
+                if let Some(progress) = download.take() {
+                    println!(
+                        "choked by peer {}, giving up piece {}",
+                        client.peer, progress.work.index
+                    );
+                    context
+                        .events
+                        .send(WorkerEvent::Release(worker, progress.work.index))?;
+                }
+            }
+            Message::Interested => {
+                client.peer_interested = true;
+                context.peer.set_interested(true);
+            }
+            Message::NotInterested => {
+                client.peer_interested = false;
+                context.peer.set_interested(false);
+            }
+            // a bitfield is only allowed before anything else, so ignore it
+            // once the peer has told us about pieces some other way
+            Message::Bitfield(mut bitfield) if client.bitfield.not_any() => {
+                bitfield.resize(context.pieces.len(), false);
+                client.bitfield = bitfield;
+                context
+                    .events
+                    .send(WorkerEvent::Pieces(worker, client.bitfield.clone()))?;
+                LeechClient::update_interest(client, context).await?;
+            }
+            // ignore anything out of range or that we already knew about
+            Message::Have { piece_index }
+                if piece_index < client.bitfield.len() && !client.bitfield[piece_index] =>
+            {
+                client.bitfield.set(piece_index, true);
+                context
+                    .events
+                    .send(WorkerEvent::Have(worker, piece_index))?;
+                LeechClient::update_interest(client, context).await?;
+            }
+            Message::Extended { id, payload } => client.handle_extended(id, &payload).await?,
+            Message::Request(block) => upload::queue_request(client, context, block),
+            Message::Cancel(block) => upload::cancel_request(client, block),
             Message::Block {
                 piece_index,
                 offset,
                 block_data,
             } => {
-                println!("block data length: {}", block_data.len());
-                piece_progress.buffer[offset as usize..(offset as usize + block_data.len())]
-                    .copy_from_slice(block_data.as_ref());
-                piece_progress.downloaded += block_data.len();
-                piece_progress.backlog -= 1;
+                context.stats.add_downloaded(block_data.len() as u64);
+                context.peer.add_downloaded(block_data.len() as u64);
+                let block = BlockInfo {
+                    piece_index,
+                    block_offset: offset,
+                    block_length: block_data.len() as u32,
+                };
+                // blocks we didn't ask for, or of a piece we've given up on,
+                // are dropped
+                let position = client.outstanding.iter().position(|asked| *asked == block);
+                if let (Some(position), Some(progress)) = (position, download.as_mut()) {
+                    client.outstanding.swap_remove(position);
+                    let end = offset as usize + block_data.len();
+                    progress.buffer[offset as usize..end].copy_from_slice(block_data.as_ref());
+                    progress.downloaded += block_data.len();
+                    if progress.downloaded == progress.work.length {
+                        let progress = download.take().unwrap();
+                        // hashed elsewhere so we can get on with the next piece
+                        context
+                            .hasher
+                            .submit(worker, progress.work, progress.buffer)
+                            .await?;
+                    }
+                }
             }
             _ => {}
         }
-        println!(
-            "message handled? {} {}",
-            piece_progress.downloaded, piece_progress.backlog
-        );
         Ok(())
     }
 
-    async fn attempt_piece_download(
-        client: &mut PeerClient,
-        piece_work: PieceWork,
-    ) -> Result<BytesMut> {
-        println!("PIECE_WORK LENGTH: {}", piece_work.length);
-        let mut piece_progress = PieceInProgress {
-            index: piece_work.index,
-            buffer: BytesMut::from(&vec![0_u8; piece_work.length][..]),
-            downloaded: 0,
-            requested: 0,
-            backlog: 0,
+    // Tells the peer whether it has anything we still want, only sending a
+    // message when that changes.
+    async fn update_interest(client: &mut PeerClient, context: &WorkerContext) -> Result<()> {
+        let interested = {
+            let wanted = context.wanted.borrow();
+            client
+                .bitfield
+                .iter_ones()
+                .any(|index| wanted.get(index).is_some_and(|bit| *bit))
         };
+        client.set_interested(interested).await
+    }
 
-        while piece_progress.downloaded < piece_work.length {
-            if !client.choked {
-                while piece_progress.backlog < MAX_BACKLOG
-                    && piece_progress.requested < piece_work.length
-                {
-                    let mut block_size = MAX_REQUEST_SIZE;
-                    if piece_work.length - piece_progress.requested < block_size {
-                        block_size = piece_work.length - piece_progress.requested;
-                    }
-
-                    client
-                        .send_message(Message::Request(BlockInfo {
-                            piece_index: piece_work.index,
-                            block_offset: piece_progress.requested as u32,
-                            block_length: block_size as u32,
-                        }))
-                        .await?;
+    // Keeps the peer's request queue topped up with blocks of the piece
+    // we're downloading.
+    async fn request_blocks(client: &mut PeerClient, progress: &mut PieceInProgress) -> Result<()> {
+        // never queue more requests than the peer said it would accept
+        let max_backlog = client
+            .peer_extensions
+            .request_queue_size()
+            .map_or(MAX_BACKLOG, |reqq| std::cmp::min(reqq, MAX_BACKLOG));
+        let length = progress.work.length;
+        while client.outstanding.len() < max_backlog && progress.requested < length {
+            let block = BlockInfo {
+                piece_index: progress.work.index,
+                block_offset: progress.requested as u32,
+                block_length: std::cmp::min(MAX_REQUEST_SIZE, length - progress.requested) as u32,
+            };
+            client.send_message(Message::Request(block)).await?;
+            client.outstanding.push(block);
+            progress.requested += block.block_length as usize;
+        }
+        Ok(())
+    }
 
-                    piece_progress.backlog += 1;
-                    piece_progress.requested += block_size;
-                    println!(
-                        "piece_progress requested: {} backlog: {}",
-                        piece_progress.requested, piece_progress.backlog
-                    );
-                }
+    // Listens on the usual port, or any free one if something else has it.
+    async fn bind_listener(&self) -> Option<Arc<PeerListener>> {
+        let handshake_timeout = self.timeouts.handshake;
+        let bound = match PeerListener::bind_with_timeout(LISTEN_PORT, handshake_timeout).await {
+            Ok(listener) => Ok(listener),
+            Err(e) => {
+                println!("unable to listen on port {}: {:?}", LISTEN_PORT, e);
+                PeerListener::bind_with_timeout(0, handshake_timeout).await
+            }
+        };
+        match bound {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                println!("not accepting peers: {:?}", e);
+                None
             }
-            println!("awaiting message from peer {}", client.peer.addr);
-            LeechClient::handle_message(&mut piece_progress, client).await?;
-            println!(
-                "handled message from peer, {} {}",
-                piece_progress.downloaded, piece_work.length
-            );
         }
-        println!("we have a piece progress buffer");
-        Ok(piece_progress.buffer)
     }
 
     pub async fn download(self) -> Result<()> {
         // let clone = Arc::new(self);
-        println!("downloading...");
+        println!("downloading {}...", self.torrent_file.info.name);
         self.initialize_download().await?;
         Ok(())
     }
 
-    pub async fn initialize_download(self) -> Result<()> {
+    pub async fn initialize_download(mut self) -> Result<()> {
         // let clone = self.clone();
-        let (work_tx, mut work_rx) =
-            broadcast::channel::<PieceWork>(self.torrent_file.piece_hashes.len());
-        let (result_tx, mut result_rx) = unbounded_channel::<PieceResult>();
-
-        let work_rx = Arc::new(Mutex::new(work_rx));
-
-        let max_peers = std::cmp::min(self.peers.len(), 50);
-        let mut peer_count = 0;
-        for peer in self.peers {
-            peer_count += 1;
-
-            let worker_tx = work_tx.clone();
-            let results_tx = result_tx.clone();
-            let mut work_rx = work_rx.clone();
-            if peer_count < max_peers {
-                tokio::spawn(async move {
-                    println!("spawning worker for peer {:?}", peer);
-                    LeechClient::start_download_worker(
-                        peer,
-                        &mut work_rx,
-                        worker_tx,
-                        results_tx,
-                        self.info_hash,
-                        self.peer_id,
-                    )
-                    .await;
-                });
-            }
-        }
-
-        for (index, hash) in self.torrent_file.piece_hashes.iter().enumerate() {
-            let work = PieceWork {
-                index,
-                hash: *hash,
-                length: self.torrent_file.calculate_piece_size(index),
+        let (result_tx, mut result_rx) = channel::<PieceResult>(MAX_PENDING_WRITES);
+        let (peer_tx, mut peer_rx) = unbounded_channel::<Peer>();
+        let (event_tx, event_rx) = unbounded_channel::<Event>();
+        let (worker_tx, mut worker_rx) = unbounded_channel::<WorkerEvent>();
+
+        let listener = match self.listener.take() {
+            Some(listener) => Some(listener),
+            None => self.bind_listener().await,
+        };
+        // without a listener nobody can reach us, the channel stays empty
+        let mut inbound_rx = match &listener {
+            Some(listener) => listener.register(self.info_hash),
+            None => unbounded_channel::<InboundPeer>().1,
+        };
+        let listen_port = listener.as_ref().map(|listener| listener.port());
+        self.extensions.set_listen_port(listen_port);
+        let extensions = Arc::new(self.extensions);
+
+        let announcer = Announcer::new(
+            self.trackers,
+            self.info_hash,
+            self.peer_id,
+            self.key,
+            self.stats.clone(),
+            peer_tx,
+        )
+        .with_port(listen_port);
+        let announcer = tokio::spawn(announcer.run(event_rx));
+
+        // Only a download that finishes while we're running is reported as
+        // completed.
+        let was_complete = self.own_pieces.all();
+        let mut done = self.own_pieces.count_ones();
+        let pieces = Arc::new(PieceWork::all(&self.torrent_file));
+        let (own_tx, own_rx) = watch::channel(self.own_pieces.clone());
+        let (complete_tx, complete_rx) = watch::channel(false);
+        let mut scheduler = Scheduler::new(
+            PiecePicker::new(
+                std::mem::take(&mut self.own_pieces),
+                self.priorities.clone(),
+            ),
+            pieces.clone(),
+        );
+        let (wanted_tx, wanted_rx) = watch::channel(scheduler.wanted_pieces());
+
+        let hasher = PieceHasher::new(result_tx);
+        let mut choker = Choker::default();
+        let spawn_worker = |workers: &mut JoinSet<()>, choker: &mut Choker, source: PeerSource| {
+            let peer_state = Arc::new(PeerState::new());
+            let context = WorkerContext {
+                info_hash: self.info_hash,
+                peer_id: self.peer_id,
+                extensions: extensions.clone(),
+                stats: self.stats.clone(),
+                hasher: hasher.clone(),
+                events: worker_tx.clone(),
+                storage: self.storage.clone(),
+                pieces: pieces.clone(),
+                own_pieces: own_rx.clone(),
+                complete: complete_rx.clone(),
+                wanted: wanted_rx.clone(),
+                peer: peer_state.clone(),
+                timeouts: self.timeouts,
             };
-            work_tx.send(work)?;
+            let worker = workers.spawn(async move {
+                let peer = source.peer();
+                println!("spawning worker for peer {:?}", peer);
+                if let Err(e) = LeechClient::start_download_worker(source, context).await {
+                    println!("worker for peer {} exited: {:?}", peer, e);
+                }
+            });
+            choker.add(worker.id(), peer_state);
+            worker.id()
+        };
+
+        let storage = self.storage.clone();
+        let file_priorities = self.priorities.to_vec();
+        tokio::task::spawn_blocking(move || storage.set_file_priorities(&file_priorities))
+            .await??;
+
+        // Peers beyond the first MAX_PEERS wait here until a worker exits.
+        let mut spare_peers: VecDeque<Peer> = self.peers.into_iter().collect();
+        // Every peer we've dialled or are waiting to dial, trackers keep
+        // handing out the same ones.  A peer is forgotten once its worker
+        // exits, so it can be tried again when a tracker next mentions it.
+        let mut known_peers: HashSet<SocketAddr> =
+            spare_peers.iter().map(|peer| peer.socket_addr).collect();
+        let mut dialled: HashMap<WorkerId, SocketAddr> = HashMap::new();
+        let mut workers = JoinSet::new();
+        while workers.len() < MAX_PEERS {
+            match spare_peers.pop_front() {
+                Some(peer) => {
+                    let worker = spawn_worker(&mut workers, &mut choker, PeerSource::Dial(peer));
+                    dialled.insert(worker, peer.socket_addr);
+                }
+                None => break,
+            }
         }
 
-        drop(work_tx);
-
-        let mut buf = BytesMut::from(&vec![0_u8; self.torrent_file.info.length][..]);
-        let mut done = 0;
-        while let Some(result) = result_rx.recv().await {
-            let (start, end) = self.torrent_file.calculate_bounds_for_piece(result.index);
-            buf[start..end].copy_from_slice(&result.buf);
-            done += 1;
-            let percent = (done as f32 / self.torrent_file.piece_hashes.len() as f32) * 100.0;
-            println!("{:.2}% completed", percent);
-            if done == self.torrent_file.piece_hashes.len() {
+        let mut completed_at = None;
+        let mut seed_check = tokio::time::interval(SEED_CHECK_INTERVAL);
+        let mut choke_round = tokio::time::interval(CHOKE_INTERVAL);
+        // Pieces verified since the resume data was last saved.  Saving means
+        // looking at every file, so it's done now and then rather than for
+        // every piece, and once more when we stop.
+        let mut resume_dirty = false;
+        let mut resume_save = tokio::time::interval(RESUME_SAVE_INTERVAL);
+        loop {
+            // files can become wanted again while we're seeding
+            if scheduler.wanted_count() > 0 {
+                if completed_at.take().is_some() {
+                    complete_tx.send_replace(false);
+                }
+            } else if completed_at.is_none() {
+                completed_at = Some(Instant::now());
+                complete_tx.send_replace(true);
+                scheduler.finish();
+                if !was_complete && scheduler.own_pieces().all() {
+                    event_tx.send(Event::Completed)?;
+                }
+                let storage = self.storage.clone();
+                tokio::task::spawn_blocking(move || storage.flush()).await??;
+                println!("download complete");
+            }
+            let length = self.torrent_file.info.length;
+            if completed_at
+                .is_some_and(|since| self.seed_limits.reached(&self.stats, length, since))
+            {
                 break;
             }
+
+            tokio::select! {
+                Some(result) = result_rx.recv() => {
+                    let index = result.work.index;
+                    if !result.verified {
+                        println!("integrity check failed for piece {}", index);
+                        scheduler.piece_hashed(result.worker, index, false);
+                        continue;
+                    }
+                    // someone else got there first
+                    if scheduler.own_pieces()[index] {
+                        continue;
+                    }
+                    let storage = self.storage.clone();
+                    let len = result.buf.len();
+                    tokio::task::spawn_blocking(move || storage.write(index, 0, &result.buf))
+                        .await??;
+                    self.stats.piece_completed(len as u64);
+                    scheduler.piece_hashed(result.worker, index, true);
+                    own_tx.send_replace(scheduler.own_pieces().clone());
+                    wanted_tx.send_replace(scheduler.wanted_pieces());
+                    resume_dirty = true;
+                    done += 1;
+                    let percent = (done as f32 / self.torrent_file.piece_count as f32) * 100.0;
+                    let hashed = hasher.stats();
+                    println!(
+                        "{:.2}% completed, hashed {} pieces ({} failed) at {:.1} MB/s",
+                        percent,
+                        hashed.pieces(),
+                        hashed.failed(),
+                        hashed.throughput()
+                    );
+                }
+                Some(peer) = peer_rx.recv() => {
+                    if !known_peers.insert(peer.socket_addr) {
+                        continue;
+                    }
+                    if workers.len() < MAX_PEERS {
+                        let worker = spawn_worker(&mut workers, &mut choker, PeerSource::Dial(peer));
+                        dialled.insert(worker, peer.socket_addr);
+                    } else {
+                        spare_peers.push_back(peer);
+                    }
+                }
+                // peers that dial us while we're full are turned away
+                Some(inbound) = inbound_rx.recv() => {
+                    if workers.len() < MAX_PEERS {
+                        spawn_worker(&mut workers, &mut choker, PeerSource::Accepted(inbound));
+                    }
+                }
+                Some(event) = worker_rx.recv() => scheduler.handle(event),
+                Some(joined) = workers.join_next_with_id() => {
+                    let worker = match joined {
+                        Ok((worker, _)) => worker,
+                        Err(e) => e.id(),
+                    };
+                    scheduler.remove_worker(worker);
+                    choker.remove(worker);
+                    if let Some(addr) = dialled.remove(&worker) {
+                        known_peers.remove(&addr);
+                    }
+                    if let Some(peer) = spare_peers.pop_front() {
+                        let worker = spawn_worker(&mut workers, &mut choker, PeerSource::Dial(peer));
+                        dialled.insert(worker, peer.socket_addr);
+                    }
+                }
+                _ = self.priorities.changed() => {
+                    let storage = self.storage.clone();
+                    let file_priorities = self.priorities.to_vec();
+                    tokio::task::spawn_blocking(move || storage.set_file_priorities(&file_priorities))
+                        .await??;
+                    scheduler.priorities_changed();
+                    wanted_tx.send_replace(scheduler.wanted_pieces());
+                }
+                _ = seed_check.tick(), if completed_at.is_some() => {}
+                _ = choke_round.tick() => choker.run_round(completed_at.is_some()),
+                _ = resume_save.tick(), if resume_dirty => {
+                    let storage = self.storage.clone();
+                    let info_hash = self.info_hash;
+                    let own_pieces = scheduler.own_pieces().clone();
+                    // saved one at a time so an older bitfield never
+                    // overwrites a newer one
+                    let saved = tokio::task::spawn_blocking(move || {
+                        resume::save(storage.as_ref(), info_hash, &own_pieces)
+                    })
+                    .await?;
+                    if let Err(e) = saved {
+                        println!("unable to save resume data: {:?}", e);
+                    }
+                    resume_dirty = false;
+                }
+                else => break,
+            }
+        }
+        if let Some(listener) = &listener {
+            listener.unregister(self.info_hash);
         }
         drop(result_rx);
+        // waiting workers find there's nothing more to ask for
+        drop(worker_rx);
+        workers.shutdown().await;
+
+        let storage = self.storage.clone();
+        let info_hash = self.info_hash;
+        let own_pieces = scheduler.own_pieces().clone();
+        tokio::task::spawn_blocking(move || {
+            storage.flush()?;
+            resume::save(storage.as_ref(), info_hash, &own_pieces)
+        })
+        .await??;
+
+        event_tx.send(Event::Stopped)?;
+        announcer.await?;
 
         Ok(())
     }
 
-    async fn start_download_worker(
-        peer: Peer,
-        work_rx: &mut Arc<Mutex<broadcast::Receiver<PieceWork>>>,
-        work_tx: broadcast::Sender<PieceWork>,
-        result_tx: UnboundedSender<PieceResult>,
-        info_hash: InfoHash,
-        peer_id: PeerId,
-    ) -> Result<()> {
-        let mut peer_client = PeerClient::new(peer, info_hash, peer_id).await?;
-
-        let _ = peer_client.send_message(Message::Unchoke).await;
-        let _ = peer_client.send_message(Message::Interested).await;
-
+    async fn start_download_worker(source: PeerSource, context: WorkerContext) -> Result<()> {
+        let own_pieces = context.own_pieces.borrow().clone();
+        let (info_hash, peer_id, extensions, timeouts) = (
+            context.info_hash,
+            context.peer_id,
+            context.extensions.clone(),
+            context.timeouts,
+        );
+        // a peer that goes quiet ends the worker with an error, and the
+        // session hands its piece to someone else
+        let mut peer_client = match source {
+            PeerSource::Dial(peer) => {
+                PeerClient::new(peer, info_hash, peer_id, extensions, own_pieces, timeouts).await?
+            }
+            PeerSource::Accepted(inbound) => {
+                PeerClient::accept(
+                    inbound, info_hash, peer_id, extensions, own_pieces, timeouts,
+                )
+                .await?
+            }
+        };
+        let worker = task::id();
+        context
+            .events
+            .send(WorkerEvent::Pieces(worker, peer_client.bitfield.clone()))?;
+
+        // the peer stays choked until the choker says otherwise
+        LeechClient::update_interest(&mut peer_client, &context).await?;
+
+        let mut own_pieces = context.own_pieces.clone();
+        let mut wanted = context.wanted.clone();
+        let mut complete = context.complete.clone();
+        let piece_count = context.pieces.len();
+        let mut download: Option<PieceInProgress> = None;
+        let mut reply_rx: Option<oneshot::Receiver<PieceWork>> = None;
+        // until the session says there's nothing left to download
+        let mut downloading = true;
+
+        // Everything the peer sends and everything the session or the choker
+        // want from the peer is dealt with here, as it happens.
         loop {
-            let piece_work = work_rx.lock().await.recv().await;
-            if let Ok(piece_work) = piece_work {
-                if peer_client.bitfield.get(piece_work.index) == None {
-                    work_tx.send(piece_work)?;
-                    continue;
+            if downloading && !peer_client.peer_choking {
+                match download.as_mut() {
+                    Some(progress) => {
+                        LeechClient::request_blocks(&mut peer_client, progress).await?
+                    }
+                    None if reply_rx.is_none() => {
+                        let (reply_tx, rx) = oneshot::channel();
+                        context
+                            .events
+                            .send(WorkerEvent::Request(worker, reply_tx))?;
+                        reply_rx = Some(rx);
+                    }
+                    None => {}
+                }
+            }
+            if !downloading {
+                // two seeds have nothing to say to each other
+                let peer_is_seed = peer_client
+                    .bitfield
+                    .get(..piece_count)
+                    .is_some_and(|pieces| pieces.all());
+                if peer_is_seed && context.own_pieces.borrow().all() {
+                    return Ok(());
                 }
+            }
+            let serving = !peer_client.am_choking && !peer_client.requests.is_empty();
 
-                let buf =
-                    match LeechClient::attempt_piece_download(&mut peer_client, piece_work).await {
-                        Ok(buf) => buf,
-                        Err(e) => {
-                            println!("attempt piece download failed: {:?}", e);
-                            work_tx.send(piece_work)?;
-                            continue;
+            tokio::select! {
+                message = peer_client.receive() => {
+                    LeechClient::handle_message(message?, &mut download, &mut peer_client, &context)
+                        .await?
+                }
+                Some(reply) = OptionFuture::from(reply_rx.as_mut()) => {
+                    reply_rx = None;
+                    match reply {
+                        // choked while we were waiting, someone else can have it
+                        Ok(piece_work) if peer_client.peer_choking => {
+                            context
+                                .events
+                                .send(WorkerEvent::Release(worker, piece_work.index))?;
                         }
-                    };
-
-                if !piece_work.check_integrity(&buf) {
-                    println!("integrity check failed");
-                    work_tx.send(piece_work)?;
-                    continue;
+                        Ok(piece_work) => download = Some(PieceInProgress::new(piece_work)),
+                        // the session stops answering once the download is
+                        // done, stay around to upload
+                        Err(_) => downloading = false,
+                    }
+                }
+                _ = context.peer.changed() => {
+                    upload::update_choke(&mut peer_client, &context).await?
+                }
+                changed = own_pieces.changed() => {
+                    // the session has gone away
+                    if changed.is_err() {
+                        return Ok(());
+                    }
+                    upload::announce_pieces(&mut peer_client, &context).await?
+                }
+                // the session going away is noticed through own_pieces
+                Ok(()) = complete.changed() => {
+                    if !*complete.borrow_and_update() && !downloading {
+                        downloading = true;
+                    }
+                }
+                // losing interest once the peer has nothing left for us, or
+                // getting it back when a file is un-skipped
+                Ok(()) = wanted.changed() => {
+                    LeechClient::update_interest(&mut peer_client, &context).await?
+                }
+                _ = async {}, if serving => {
+                    upload::serve_request(&mut peer_client, &context).await?
                 }
-
-                println!("we got a buffer???????");
-
-                peer_client
-                    .send_message(Message::Have {
-                        piece_index: piece_work.index,
-                    })
-                    .await?;
-
-                result_tx.send(PieceResult {
-                    index: piece_work.index,
-                    buf,
-                })?;
-            } else {
-                break;
             }
         }
-        Ok(())
-    }
-
-    async fn poll_tracker(&mut self) -> Result<()> {
-        let req = TrackerRequest::new_from_torrent(&self.torrent_file, self.peer_id);
-        let res = reqwest::get(&req.to_string())
-            .await
-            .expect("Request failed");
-        let body = res.bytes().await.expect("could not parse response body");
-        let res = de::from_bytes::<TrackerResponse>(&body)?;
-        self.poll_interval = res.interval;
-        self.set_peers(res.peers);
-        Ok(())
     }
 }
 
 fn generate_peer_id() -> PeerId {
     rand::thread_rng().gen::<PeerId>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LENGTH: usize = 1000;
+
+    fn uploaded(bytes: u64) -> TransferStats {
+        let stats = TransferStats::new(0);
+        stats.add_uploaded(bytes);
+        stats
+    }
+
+    fn completed_ago(secs: u64) -> Instant {
+        Instant::now() - Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn no_seed_limits() {
+        let limits = SeedLimits::default();
+        assert!(limits.reached(&uploaded(0), LENGTH, Instant::now()));
+    }
+
+    #[test]
+    fn seed_ratio() {
+        let limits = SeedLimits {
+            ratio: Some(1.5),
+            time: None,
+        };
+        assert!(!limits.reached(&uploaded(1499), LENGTH, completed_ago(3600)));
+        assert!(limits.reached(&uploaded(1500), LENGTH, Instant::now()));
+    }
+
+    #[test]
+    fn seed_time() {
+        let limits = SeedLimits {
+            ratio: None,
+            time: Some(Duration::from_secs(60)),
+        };
+        assert!(!limits.reached(&uploaded(10 * LENGTH as u64), LENGTH, completed_ago(59)));
+        assert!(limits.reached(&uploaded(0), LENGTH, completed_ago(60)));
+    }
+
+    #[test]
+    fn whichever_seed_limit_comes_first() {
+        let limits = SeedLimits {
+            ratio: Some(2.0),
+            time: Some(Duration::from_secs(60)),
+        };
+        assert!(!limits.reached(&uploaded(LENGTH as u64), LENGTH, completed_ago(30)));
+        assert!(limits.reached(&uploaded(2 * LENGTH as u64), LENGTH, completed_ago(30)));
+        assert!(limits.reached(&uploaded(LENGTH as u64), LENGTH, completed_ago(60)));
+    }
+}
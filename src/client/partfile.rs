@@ -0,0 +1,167 @@
+use super::types::PieceIndex;
+
+use anyhow::{anyhow, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+// Holds pieces that belong partly to files we aren't downloading.  A piece
+// on the boundary between a wanted and a skipped file still has to be
+// downloaded and verified as a whole, and the skipped file's share of it
+// needs to go somewhere without creating that file.
+//
+// The file starts with a 4 byte entry per piece giving the slot the piece is
+// stored in plus one, zero meaning it has none.  Slots are `piece_length`
+// bytes each and follow the header in the order they were handed out.
+#[derive(Debug)]
+pub(crate) struct PartFile {
+    path: PathBuf,
+    piece_length: usize,
+    slots: Vec<Option<u32>>,
+    // Created on first write.
+    file: Option<File>,
+}
+
+impl PartFile {
+    // A read-only partfile can still be read from, but never written to.
+    pub(crate) fn open(
+        path: PathBuf,
+        piece_count: usize,
+        piece_length: usize,
+        read_only: bool,
+    ) -> Result<Self> {
+        let mut slots = vec![None; piece_count];
+        let file = match OpenOptions::new().read(true).write(!read_only).open(&path) {
+            Ok(mut file) => {
+                let mut header = vec![0; piece_count * 4];
+                file.read_exact(&mut header)?;
+                for (slot, entry) in slots.iter_mut().zip(header.chunks_exact(4)) {
+                    *slot = BigEndian::read_u32(entry).checked_sub(1);
+                }
+                Some(file)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(PartFile {
+            path,
+            piece_length,
+            slots,
+            file,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub(crate) fn has(&self, index: PieceIndex) -> bool {
+        self.slots[index].is_some()
+    }
+
+    pub(crate) fn write(&mut self, index: PieceIndex, offset: usize, data: &[u8]) -> Result<()> {
+        let header_len = self.slots.len() as u64 * 4;
+        let slot = match self.slots[index] {
+            Some(slot) => slot,
+            None => {
+                let slot = self.slots.iter().flatten().count() as u32;
+                let file = self.file()?;
+                file.seek(SeekFrom::Start(index as u64 * 4))?;
+                file.write_all(&(slot + 1).to_be_bytes())?;
+                self.slots[index] = Some(slot);
+                slot
+            }
+        };
+        let position = header_len + slot as u64 * self.piece_length as u64 + offset as u64;
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(position))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    pub(crate) fn read(&mut self, index: PieceIndex, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let header_len = self.slots.len() as u64 * 4;
+        let slot =
+            self.slots[index].ok_or_else(|| anyhow!("piece {} is not in the partfile", index))?;
+        let position = header_len + slot as u64 * self.piece_length as u64 + offset as u64;
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| anyhow!("partfile does not exist"))?;
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(buf)?;
+        Ok(())
+    }
+
+    pub(crate) fn sync(&self) -> Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn file(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.path)?;
+            file.write_all(&vec![0; self.slots.len() * 4])?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn created_on_first_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.parts");
+        let mut part_file = PartFile::open(path.clone(), 4, 8, false).unwrap();
+        assert!(!part_file.has(2));
+        assert!(part_file.read(2, 0, &mut [0; 8]).is_err());
+        assert!(!path.exists());
+
+        part_file.write(2, 4, &[2; 4]).unwrap();
+        part_file.write(0, 0, &[1; 8]).unwrap();
+        assert!(part_file.has(2) && part_file.has(0) && !part_file.has(1));
+        let mut buf = [0; 4];
+        part_file.read(2, 4, &mut buf).unwrap();
+        assert_eq!(buf, [2; 4]);
+        // a header entry per piece, then a slot for each piece stored
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * 4 + 8 + 8);
+    }
+
+    #[test]
+    fn slots_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.parts");
+        let mut part_file = PartFile::open(path.clone(), 4, 8, false).unwrap();
+        part_file.write(3, 0, &[3; 8]).unwrap();
+        part_file.write(1, 0, &[1; 8]).unwrap();
+        part_file.sync().unwrap();
+
+        let mut part_file = PartFile::open(path, 4, 8, true).unwrap();
+        assert!(part_file.has(1) && part_file.has(3) && !part_file.has(0));
+        let mut buf = [0; 8];
+        part_file.read(3, 0, &mut buf).unwrap();
+        assert_eq!(buf, [3; 8]);
+        part_file.read(1, 0, &mut buf).unwrap();
+        assert_eq!(buf, [1; 8]);
+    }
+
+    #[test]
+    fn truncated_header_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.parts");
+        std::fs::write(&path, [0; 6]).unwrap();
+        assert!(PartFile::open(path, 4, 8, false).is_err());
+    }
+}
@@ -1,8 +1,10 @@
-use super::types::PeerAddr;
-
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ByteOrder};
 use std::fmt;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Length of a compact peer entry, an address followed by a two byte port.
+pub const COMPACT_PEER_LEN: usize = 6;
+pub const COMPACT_PEER6_LEN: usize = 18;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Peer {
@@ -13,32 +15,77 @@ pub struct Peer {
 }
 
 impl fmt::Display for Peer {
+    // SocketAddr puts IPv6 addresses in brackets so the port can't be
+    // mistaken for part of the address.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}", self.addr, self.port)
+        write!(f, "{}", self.socket_addr)
     }
 }
 
-impl From<PeerAddr> for Peer {
-    // PeerIds are given as chunks of 6 bytes in compact mode, like so:
+impl Peer {
+    // Peers are given as chunks of 6 bytes in compact mode, like so:
     // [192, 0, 2, 123, 26, 225].  The first four bytes are the IP, and the
     // last two are the port address in BigEndian format. To get the port
     // you just squish the two together.
     // Eg: [26, 225] or [0x1A, 0xE1] -> 6881
-    fn from(peer_addr: PeerAddr) -> Self {
-        let addr = &peer_addr[0..4];
-        let mut port = &peer_addr[4..6];
-
-        // There's no way I could find to spread the slice to the function arguments,
-        // but we're only using IPv4 so it should be fine.
-        let addr = IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]));
-        let port = port.read_u16::<BigEndian>().expect("port parse failed");
-        let socket_addr = SocketAddr::new(addr, port);
+    // IPv6 peers (BEP 7) are the same thing with a 16 byte address, 18 bytes
+    // in all.  Entries of any other length aren't peers.
+    pub fn from_compact(entry: &[u8]) -> Option<Self> {
+        let addr = match entry.len() {
+            COMPACT_PEER_LEN => {
+                let octets: [u8; 4] = entry[0..4].try_into().ok()?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            COMPACT_PEER6_LEN => {
+                let octets: [u8; 16] = entry[0..16].try_into().ok()?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        let port = BigEndian::read_u16(&entry[entry.len() - 2..]);
+        Some(Peer::from(SocketAddr::new(addr, port)))
+    }
+}
 
+impl From<SocketAddr> for Peer {
+    fn from(socket_addr: SocketAddr) -> Self {
         Peer {
-            addr,
-            port,
+            addr: socket_addr.ip(),
+            port: socket_addr.port(),
             socket_addr,
             piece_count: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_ipv4() {
+        let peer = Peer::from_compact(&[192, 0, 2, 123, 0x1a, 0xe1]).unwrap();
+        assert_eq!(peer.addr, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 123)));
+        assert_eq!(peer.port, 6881);
+        assert_eq!(peer.to_string(), "192.0.2.123:6881");
+    }
+
+    #[test]
+    fn compact_ipv6() {
+        let mut entry = vec![0x20, 0x01, 0x0d, 0xb8];
+        entry.extend([0; 11]);
+        entry.extend([1, 0x1a, 0xe1]);
+        let peer = Peer::from_compact(&entry).unwrap();
+        assert_eq!(peer.addr, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(peer.port, 6881);
+        assert_eq!(peer.to_string(), "[2001:db8::1]:6881");
+    }
+
+    #[test]
+    fn other_lengths_are_not_peers() {
+        assert_eq!(Peer::from_compact(&[]), None);
+        assert_eq!(Peer::from_compact(&[1, 2, 3, 4, 5]), None);
+        assert_eq!(Peer::from_compact(&[0; 7]), None);
+        assert_eq!(Peer::from_compact(&[0; 17]), None);
+    }
+}
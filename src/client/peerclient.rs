@@ -1,87 +1,394 @@
+use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::sync::Arc;
 use std::time::Duration;
 
+use super::block::BlockInfo;
+use super::extension::{ExtendedHandshake, ExtensionRegistry, HANDSHAKE_ID};
 use super::handshake::{Handshake, HandshakeCodec};
+use super::listener::InboundPeer;
 use super::message::Message;
 use super::message::PeerCodec;
 use super::peer::Peer;
 use super::types::{Bitfield, InfoHash, PeerId};
 
 use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_util::codec::{Framed, FramedParts};
 
+type PeerSocket = Framed<TcpStream, PeerCodec>;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Longer than the keep-alive interval, so a quiet but well-behaved peer
+// isn't dropped.
+const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(180);
+// Peers drop connections that have gone quiet for a couple of minutes.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+// How long we wait on a peer before giving up on it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeerTimeouts {
+    pub(crate) connect: Duration,
+    // Covers both handshakes and everything we send before the connection
+    // is handed over.
+    pub(crate) handshake: Duration,
+    // How long the peer may go without sending anything, keep-alives
+    // included.
+    pub(crate) inactivity: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        PeerTimeouts {
+            connect: CONNECT_TIMEOUT,
+            handshake: HANDSHAKE_TIMEOUT,
+            inactivity: INACTIVITY_TIMEOUT,
+        }
+    }
+}
+
+// One end of a connection to a peer, from the handshake on.  Reads and
+// writes go through the same framed socket for the life of the connection,
+// so nothing the peer sends is lost between messages.
 #[derive(Debug)]
 pub struct PeerClient {
     pub peer: Peer,
+    // What the peer has told us it has.
     pub bitfield: Bitfield,
-    pub connection: TcpStream,
-    info_hash: InfoHash,
-    peer_id: PeerId,
-    pub choked: bool,
+    sink: SplitSink<PeerSocket, Message>,
+    stream: SplitStream<PeerSocket>,
+    // whether messages have been queued since the socket was last flushed
+    unflushed: bool,
+    last_received: Instant,
+    last_sent: Instant,
+    inactivity_timeout: Duration,
+    // whether the peer is choking us
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    // whether we're choking the peer
+    pub am_choking: bool,
+    pub am_interested: bool,
+    // What we've told the peer we have.
+    pub own_pieces: Bitfield,
+    // Blocks the peer has asked for that we haven't sent yet.
+    pub requests: VecDeque<BlockInfo>,
+    // Blocks we've asked the peer for that haven't arrived yet.
+    pub outstanding: Vec<BlockInfo>,
+    extensions: Arc<ExtensionRegistry>,
+    // What the peer told us about itself in its extension handshake, left
+    // empty if it doesn't support the extension protocol.
+    pub peer_extensions: ExtendedHandshake,
 }
 
 impl PeerClient {
-    pub async fn new(peer: Peer, info_hash: InfoHash, peer_id: PeerId) -> Result<Self> {
-        let mut connection = TcpStream::connect(peer.socket_addr).await?;
+    pub async fn new(
+        peer: Peer,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        extensions: Arc<ExtensionRegistry>,
+        own_pieces: Bitfield,
+        timeouts: PeerTimeouts,
+    ) -> Result<Self> {
+        let connection = timeout(timeouts.connect, TcpStream::connect(peer.socket_addr))
+            .await
+            .map_err(|_| anyhow!("timed out connecting to {}", peer))??;
         println!("socked created to peer {}", peer.socket_addr);
 
-        let _ = initial_handshake(&mut connection, info_hash, peer_id).await?;
+        let mut socket = Framed::new(connection, HandshakeCodec);
+        timeout(timeouts.handshake, async move {
+            let peer_handshake = initial_handshake(&mut socket, info_hash, peer_id).await?;
+            PeerClient::start(
+                peer,
+                socket,
+                peer_handshake,
+                extensions,
+                own_pieces,
+                timeouts,
+            )
+            .await
+        })
+        .await
+        .map_err(|_| anyhow!("timed out handshaking with {}", peer))?
+    }
+
+    // Takes over a connection the peer opened, once the listener has read its
+    // handshake and found it's for our torrent.
+    pub async fn accept(
+        inbound: InboundPeer,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        extensions: Arc<ExtensionRegistry>,
+        own_pieces: Bitfield,
+        timeouts: PeerTimeouts,
+    ) -> Result<Self> {
+        let InboundPeer {
+            peer,
+            mut socket,
+            handshake,
+        } = inbound;
+        timeout(timeouts.handshake, async move {
+            socket.send(Handshake::new(info_hash, peer_id)).await?;
+            PeerClient::start(peer, socket, handshake, extensions, own_pieces, timeouts).await
+        })
+        .await
+        .map_err(|_| anyhow!("timed out handshaking with {}", peer))?
+    }
 
-        let bitfield = receive_bitfield(&mut connection).await?;
+    // Everything after the handshakes is the same whoever dialled.  The
+    // peer's bitfield and extension handshake turn up as ordinary messages.
+    async fn start(
+        peer: Peer,
+        socket: Framed<TcpStream, HandshakeCodec>,
+        peer_handshake: Handshake,
+        extensions: Arc<ExtensionRegistry>,
+        own_pieces: Bitfield,
+        timeouts: PeerTimeouts,
+    ) -> Result<Self> {
+        let mut socket = into_peer_codec(socket);
+        // the bitfield has to come first, and can be left out when we have
+        // nothing
+        if own_pieces.any() {
+            socket.feed(Message::Bitfield(own_pieces.clone())).await?;
+        }
+        if peer_handshake.supports_extension_protocol() {
+            socket
+                .feed(Message::Extended {
+                    id: HANDSHAKE_ID,
+                    payload: serde_bencode::to_bytes(&extensions.handshake())?,
+                })
+                .await?;
+        }
+        socket.flush().await?;
 
+        let (sink, stream) = socket.split();
         Ok(PeerClient {
             peer,
-            connection,
-            bitfield,
-            info_hash,
-            peer_id,
-            choked: true,
+            // peers with nothing may not send a bitfield at all
+            bitfield: Bitfield::repeat(false, own_pieces.len()),
+            sink,
+            stream,
+            unflushed: false,
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            inactivity_timeout: timeouts.inactivity,
+            peer_choking: true,
+            peer_interested: false,
+            am_choking: true,
+            am_interested: false,
+            own_pieces,
+            requests: VecDeque::new(),
+            outstanding: Vec::new(),
+            extensions,
+            peer_extensions: ExtendedHandshake::default(),
         })
     }
 
+    // Queues a message for the peer.  It goes out while we wait for the
+    // next message from the peer, or sooner if enough has piled up.
     pub async fn send_message(&mut self, message: Message) -> Result<()> {
-        let mut stream = Framed::new(&mut self.connection, PeerCodec);
-        let _ = stream.send(message).await?;
+        self.sink.feed(message).await?;
+        self.unflushed = true;
+        self.last_sent = Instant::now();
         Ok(())
     }
 
-    pub async fn handle_message(&mut self) -> Result<Message> {
-        let mut socket = Framed::new(&mut self.connection, PeerCodec);
+    // Waits for the next message from the peer, writing out whatever has
+    // been queued in the meantime.  Nothing is lost if the wait is given up
+    // on, so it can be raced against other events.  Fails if the peer has
+    // gone quiet for too long.
+    pub async fn receive(&mut self) -> Result<Message> {
         loop {
-            let msg = socket.next().await;
-            if let Some(Ok(msg)) = msg {
-                return Ok(msg);
-            }
-            if let Some(Err(e)) = msg {
-                return Err(anyhow!("{}", e));
+            tokio::select! {
+                message = self.stream.next() => {
+                    self.last_received = Instant::now();
+                    return message.ok_or_else(|| anyhow!("connection closed by peer {}", self.peer))?;
+                }
+                flushed = self.sink.flush(), if self.unflushed => {
+                    flushed?;
+                    self.unflushed = false;
+                }
+                _ = sleep_until(self.last_sent + KEEP_ALIVE_INTERVAL) => {
+                    self.send_message(Message::KeepAlive).await?;
+                }
+                _ = sleep_until(self.last_received + self.inactivity_timeout) => {
+                    return Err(anyhow!(
+                        "peer {} sent nothing for {:?}",
+                        self.peer,
+                        self.inactivity_timeout
+                    ));
+                }
             }
         }
     }
-}
 
-async fn receive_bitfield(connection: &mut TcpStream) -> Result<Bitfield> {
-    let mut socket = Framed::new(connection, PeerCodec);
-    loop {
-        let msg = socket.next().await;
-        if let Some(Ok(Message::Bitfield(bitfield))) = msg {
-            return Ok(bitfield);
+    // Tells the peer whether we want anything from it, if that has changed.
+    pub async fn set_interested(&mut self, interested: bool) -> Result<()> {
+        if interested != self.am_interested {
+            let message = if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            };
+            self.send_message(message).await?;
+            self.am_interested = interested;
+        }
+        Ok(())
+    }
+
+    // Dispatches an extended message to the registered extension it is
+    // addressed to, sending back whatever reply the extension produces.
+    pub async fn handle_extended(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        if id == HANDSHAKE_ID {
+            // Peers are allowed to send further handshakes to update what
+            // they support.
+            self.peer_extensions = serde_bencode::from_bytes(payload)?;
+            return Ok(());
+        }
+
+        let extensions = self.extensions.clone();
+        let handler = match extensions.handler(id) {
+            Some(handler) => handler,
+            None => return Ok(()),
+        };
+        let reply = handler.handle_message(payload)?;
+        if let (Some(payload), Some(id)) =
+            (reply, self.peer_extensions.extension_id(handler.name()))
+        {
+            self.send_message(Message::Extended { id, payload }).await?;
         }
+        Ok(())
     }
 }
 
+// Switches a connection from the handshake codec to the peer message codec.
+// Anything the peer sent straight after its handshake is already sitting in
+// the handshake codec's read buffer, so it is carried over.
+pub fn into_peer_codec<T>(socket: Framed<T, HandshakeCodec>) -> Framed<T, PeerCodec>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let parts = socket.into_parts();
+    let mut new_parts = FramedParts::new(parts.io, PeerCodec);
+    new_parts.read_buf = parts.read_buf;
+    new_parts.write_buf = parts.write_buf;
+    Framed::from_parts(new_parts)
+}
+
 async fn initial_handshake(
-    connection: &mut TcpStream,
+    socket: &mut Framed<TcpStream, HandshakeCodec>,
     info_hash: InfoHash,
     peer_id: PeerId,
-) -> Result<()> {
-    let mut socket = Framed::new(connection, HandshakeCodec);
+) -> Result<Handshake> {
     let handshake = Handshake::new(info_hash, peer_id);
-    let _ = socket.send(handshake).await?;
+    socket.send(handshake).await?;
+
+    let peer_handshake = socket
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("connection closed during handshake"))??;
+    // the peer is serving some other torrent
+    if peer_handshake.info_hash != info_hash {
+        return Err(anyhow!("handshake for another torrent"));
+    }
+    println!("handshake complete: {:?}", peer_handshake);
+    Ok(peer_handshake)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::time;
 
-    if let Some(peer_handshake) = socket.next().await {
-        let peer_handshake = peer_handshake?;
-        println!("handshake complete: {:?}", peer_handshake);
+    // Dials a peer on the loopback interface that answers our handshake with
+    // one for `peer_info_hash`.
+    async fn handshake_with(peer_info_hash: InfoHash) -> Result<Handshake> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = Framed::new(socket, HandshakeCodec);
+            socket.next().await.unwrap().unwrap();
+            socket
+                .send(Handshake::new(peer_info_hash, [2; 20]))
+                .await
+                .unwrap();
+            socket
+        });
+        let mut socket = Framed::new(TcpStream::connect(addr).await.unwrap(), HandshakeCodec);
+        let handshake = initial_handshake(&mut socket, [1; 20], [3; 20]).await;
+        peer.await.unwrap();
+        handshake
+    }
+
+    #[tokio::test]
+    async fn handshake_for_our_torrent() {
+        let handshake = handshake_with([1; 20]).await.unwrap();
+        assert_eq!(handshake.peer_id, [2; 20]);
+    }
+
+    #[tokio::test]
+    async fn handshake_for_another_torrent() {
+        assert!(handshake_with([9; 20]).await.is_err());
+    }
+
+    // Connects a client to a peer on the loopback interface, returning the
+    // peer's end once both handshakes are done.
+    pub(crate) async fn connect() -> (PeerClient, Framed<TcpStream, PeerCodec>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = Framed::new(socket, HandshakeCodec);
+            socket.next().await.unwrap().unwrap();
+            socket.send(Handshake::new([1; 20], [2; 20])).await.unwrap();
+            into_peer_codec(socket)
+        });
+        let client = PeerClient::new(
+            Peer::from(addr),
+            [1; 20],
+            [3; 20],
+            Arc::new(ExtensionRegistry::new()),
+            Bitfield::repeat(false, 1),
+            PeerTimeouts::default(),
+        )
+        .await
+        .unwrap();
+        (client, peer.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn silent_peer_is_dropped() {
+        let (mut client, _peer) = connect().await;
+        // connected on real time, the clock only stands still from here
+        time::pause();
+        let start = Instant::now();
+        assert!(client.receive().await.is_err());
+        assert!(start.elapsed() >= INACTIVITY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn keep_alive_after_idle_interval() {
+        let (mut client, mut peer) = connect().await;
+        time::pause();
+        let start = Instant::now();
+        let keep_alive = async {
+            loop {
+                // our extension handshake comes first
+                if let Message::KeepAlive = peer.next().await.unwrap().unwrap() {
+                    return;
+                }
+            }
+        };
+        let (received, _) = tokio::join!(client.receive(), keep_alive);
+        // nothing else was sent, so the client still gives up on the peer
+        assert!(received.is_err());
+        let sent_after = client.last_sent - start;
+        assert!(sent_after >= KEEP_ALIVE_INTERVAL);
+        assert!(sent_after < INACTIVITY_TIMEOUT);
     }
-    Ok(())
 }
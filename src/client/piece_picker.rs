@@ -1,61 +1,295 @@
+use super::priority::{FilePriorities, FilePriority};
 use super::types::{Bitfield, PieceIndex};
 
+use rand::Rng;
+use std::cmp::{Ordering, Reverse};
+use std::sync::Arc;
+
+#[derive(Debug)]
 pub struct PiecePicker {
     // pieces we have
     own_pieces: Bitfield,
-    //
     pieces: Vec<Piece>,
-    // count of pieces we do not have yet, but may have picked
-    missing_count: usize,
     // number of pieces we can still pick
     free_count: usize,
+    priorities: Arc<FilePriorities>,
+    // Pieces whose files are all skipped, and how many pieces we still want,
+    // as of the last time priorities were looked at.  Kept so the count
+    // doesn't mean taking the priorities lock for every missing piece.
+    skipped: Bitfield,
+    wanted_count: usize,
 }
 
+#[derive(Debug, Default, Clone)]
 pub struct Piece {
-    // will be important later for optimizations
-    // such as starting with the rarest pieces
+    // how many of our peers have the piece, the rarest pieces are picked
+    // first so they spread through the swarm before their owners leave
     pub frequency: usize,
     pub is_pending: bool,
 }
 
 impl PiecePicker {
-    pub fn new(own_pieces: Bitfield) -> Self {
-        let pieces = vec![own_pieces.len(), Piece::default];
+    pub fn new(own_pieces: Bitfield, priorities: Arc<FilePriorities>) -> Self {
+        let pieces = vec![Piece::default(); own_pieces.len()];
         let missing_count = own_pieces.count_zeros();
-        Self {
+        let mut picker = Self {
+            skipped: Bitfield::repeat(false, own_pieces.len()),
             own_pieces,
             pieces,
-            missing_count,
             free_count: missing_count,
+            priorities,
+            wanted_count: missing_count,
+        };
+        picker.priorities_changed();
+        picker
+    }
+
+    // Has another look at which files are skipped.
+    pub fn priorities_changed(&mut self) {
+        for index in 0..self.skipped.len() {
+            let skipped = self.priorities.piece_priority(index) == FilePriority::Skip;
+            self.skipped.set(index, skipped);
         }
+        self.wanted_count = self
+            .own_pieces
+            .iter_zeros()
+            .filter(|index| !self.skipped[*index])
+            .count();
     }
 
-    pub fn pick_piece(&mut self) -> Option<PieceIndex> {
-        for index in 0..self.pieces.len() {
-            let piece = &mut self.pieces[index];
-            if !self.own_pieces[index] && piece.frequency > 0 && !piece.is_pending {
-                piece.is_pending = true;
-                self.free_count -= 1;
-                return Some(index);
+    pub fn own_pieces(&self) -> &Bitfield {
+        &self.own_pieces
+    }
+
+    // Pieces we don't have and whose files we haven't skipped.
+    pub fn wanted_count(&self) -> usize {
+        self.wanted_count
+    }
+
+    pub fn is_wanted(&self, index: PieceIndex) -> bool {
+        !self.own_pieces[index] && !self.skipped[index]
+    }
+
+    // Every piece, set where `is_wanted`.
+    pub fn wanted_pieces(&self) -> Bitfield {
+        (0..self.pieces.len())
+            .map(|index| self.is_wanted(index))
+            .collect()
+    }
+
+    // Picks the piece to download next from a peer that has `peer_pieces`.
+    // Pieces from the most wanted files come first, then the rarest, with
+    // ties broken at random so peers don't all pile onto the same piece.
+    pub fn pick_piece(&mut self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        if self.free_count == 0 {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        let mut best: Option<(PieceIndex, (FilePriority, Reverse<usize>))> = None;
+        let mut ties = 0;
+        for index in self.own_pieces.iter_zeros() {
+            let piece = &self.pieces[index];
+            if piece.is_pending || !peer_pieces.get(index).is_some_and(|bit| *bit) {
+                continue;
+            }
+            let priority = self.priorities.piece_priority(index);
+            if priority == FilePriority::Skip {
+                continue;
+            }
+            let key = (priority, Reverse(piece.frequency));
+            match best.map(|(_, best_key)| key.cmp(&best_key)) {
+                None | Some(Ordering::Greater) => {
+                    best = Some((index, key));
+                    ties = 1;
+                }
+                Some(Ordering::Equal) => {
+                    // every tied piece ends up with the same chance of
+                    // being the one picked
+                    ties += 1;
+                    if rng.gen_range(0..ties) == 0 {
+                        best = Some((index, key));
+                    }
+                }
+                Some(Ordering::Less) => {}
             }
         }
-        None
+        let (index, _) = best?;
+        self.pieces[index].is_pending = true;
+        self.free_count -= 1;
+        Some(index)
+    }
+
+    // Gives back a piece that was picked but couldn't be downloaded, so it
+    // can be picked again.
+    pub fn unpick_piece(&mut self, index: PieceIndex) {
+        let piece = &mut self.pieces[index];
+        if piece.is_pending && !self.own_pieces[index] {
+            piece.is_pending = false;
+            self.free_count += 1;
+        }
+    }
+
+    // Returns whether the peer has any piece we don't.
+    pub fn register_peer_pieces(&mut self, peer_pieces: &Bitfield) -> bool {
+        let piece_count = self.pieces.len();
+        let mut is_interested = false;
+        for index in peer_pieces.iter_ones().take_while(|i| *i < piece_count) {
+            is_interested |= self.register_peer_piece(index);
+        }
+        is_interested
     }
 
     pub fn register_peer_piece(&mut self, index: PieceIndex) -> bool {
-        let is_interested = self.own_pieces.get(index).expect("invalid piece index");
-        self.pieces[index].frequency += 1;
-        *is_interested
+        match self.pieces.get_mut(index) {
+            Some(piece) => {
+                piece.frequency += 1;
+                !self.own_pieces[index]
+            }
+            None => false,
+        }
+    }
+
+    // Called when a peer goes away, with everything it had.
+    pub fn unregister_peer_pieces(&mut self, peer_pieces: &Bitfield) {
+        let piece_count = self.pieces.len();
+        for index in peer_pieces.iter_ones().take_while(|i| *i < piece_count) {
+            let piece = &mut self.pieces[index];
+            piece.frequency = piece.frequency.saturating_sub(1);
+        }
     }
 
     pub fn received_piece(&mut self, index: PieceIndex) {
-        let mut have_piece = self.own_pieces.get_mut(index).expect("invalid piece index");
-        *have_piece = true;
-        self.missing_count -= 1;
+        if self.own_pieces[index] {
+            return;
+        }
+        self.own_pieces.set(index, true);
+        if !self.skipped[index] {
+            self.wanted_count -= 1;
+        }
         let piece = &mut self.pieces[index];
         if !piece.is_pending {
             self.free_count -= 1;
-            piece.is_pending = false;
         }
+        piece.is_pending = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::torrent::test_helpers::bitfield;
+    use crate::client::torrent::TorrentFile;
+
+    // One piece per file.
+    fn picker(own_pieces: &[bool]) -> (PiecePicker, Arc<FilePriorities>) {
+        let files = vec![[0; 16]; own_pieces.len()];
+        let files: Vec<&[u8]> = files.iter().map(|file| &file[..]).collect();
+        let torrent = TorrentFile::for_tests(16, &files);
+        let priorities = Arc::new(FilePriorities::new(&torrent));
+        let own_pieces = bitfield(own_pieces);
+        (PiecePicker::new(own_pieces, priorities.clone()), priorities)
+    }
+
+    #[test]
+    fn wanted_count_follows_priorities() {
+        let (mut picker, priorities) = picker(&[true, false, false, false]);
+        assert_eq!(picker.wanted_count(), 3);
+
+        priorities.set(1, FilePriority::Skip).unwrap();
+        priorities.set(0, FilePriority::Skip).unwrap();
+        // not looked at until we're told
+        assert_eq!(picker.wanted_count(), 3);
+        picker.priorities_changed();
+        assert_eq!(picker.wanted_count(), 2);
+        assert!(!picker.is_wanted(1));
+        assert_eq!(
+            picker.wanted_pieces(),
+            bitfield(&[false, false, true, true])
+        );
+
+        picker.received_piece(2);
+        assert_eq!(picker.wanted_count(), 1);
+        // skipped pieces can still arrive, they don't count
+        picker.received_piece(1);
+        picker.received_piece(1);
+        assert_eq!(picker.wanted_count(), 1);
+
+        priorities.set(1, FilePriority::High).unwrap();
+        picker.priorities_changed();
+        assert_eq!(picker.wanted_count(), 1);
+        picker.received_piece(3);
+        assert_eq!(picker.wanted_count(), 0);
+    }
+
+    #[test]
+    fn rarest_first() {
+        let (mut picker, _) = picker(&[false, false, false]);
+        assert!(picker.register_peer_pieces(&bitfield(&[true, true, true])));
+        picker.register_peer_pieces(&bitfield(&[true, false, true]));
+        picker.register_peer_pieces(&bitfield(&[false, false, true]));
+        let everything = bitfield(&[true, true, true]);
+        assert_eq!(picker.pick_piece(&everything), Some(1));
+        assert_eq!(picker.pick_piece(&everything), Some(0));
+        assert_eq!(picker.pick_piece(&everything), Some(2));
+        // everything is pending
+        assert_eq!(picker.pick_piece(&everything), None);
+    }
+
+    #[test]
+    fn only_pieces_the_peer_has() {
+        let (mut picker, _) = picker(&[false, false, false]);
+        assert_eq!(picker.pick_piece(&bitfield(&[false, false, false])), None);
+        assert_eq!(picker.pick_piece(&bitfield(&[false, false, true])), Some(2));
+        // a peer with a short bitfield
+        assert_eq!(picker.pick_piece(&bitfield(&[true])), Some(0));
+    }
+
+    #[test]
+    fn priority_before_rarity() {
+        let (mut picker, priorities) = picker(&[false, false, false]);
+        picker.register_peer_pieces(&bitfield(&[true, true, false]));
+        priorities.set(0, FilePriority::Skip).unwrap();
+        priorities.set(2, FilePriority::High).unwrap();
+        let everything = bitfield(&[true, true, true]);
+        assert_eq!(picker.pick_piece(&everything), Some(2));
+        assert_eq!(picker.pick_piece(&everything), Some(1));
+        assert_eq!(picker.pick_piece(&everything), None);
+    }
+
+    #[test]
+    fn own_pieces_are_not_picked() {
+        let (mut picker, _) = picker(&[true, false]);
+        // a peer with only what we have is no use to us
+        assert!(!picker.register_peer_pieces(&bitfield(&[true, false])));
+        assert_eq!(picker.pick_piece(&bitfield(&[true, false])), None);
+    }
+
+    #[test]
+    fn unpicked_pieces_can_be_picked_again() {
+        let (mut picker, _) = picker(&[false]);
+        let everything = bitfield(&[true]);
+        assert_eq!(picker.pick_piece(&everything), Some(0));
+        assert_eq!(picker.pick_piece(&everything), None);
+        picker.unpick_piece(0);
+        picker.unpick_piece(0);
+        assert_eq!(picker.pick_piece(&everything), Some(0));
+        picker.received_piece(0);
+        // too late to give it back
+        picker.unpick_piece(0);
+        assert_eq!(picker.pick_piece(&everything), None);
+    }
+
+    #[test]
+    fn departed_peers_are_forgotten() {
+        let (mut picker, _) = picker(&[false, false]);
+        let first = bitfield(&[true, false]);
+        picker.register_peer_pieces(&first);
+        picker.register_peer_pieces(&first);
+        picker.register_peer_piece(1);
+        // once more than was registered, piece 0 ends up the rarer one
+        picker.unregister_peer_pieces(&first);
+        picker.unregister_peer_pieces(&first);
+        picker.unregister_peer_pieces(&first);
+        assert_eq!(picker.pick_piece(&bitfield(&[true, true])), Some(0));
     }
 }
@@ -0,0 +1,83 @@
+use super::torrent::TorrentFile;
+use super::types::PieceIndex;
+
+use anyhow::{anyhow, Result};
+use std::ops::Range;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+// How much we want a file.  Skipped files are never downloaded, the others
+// are fetched highest priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+// The priority of every file in a torrent, shared between whoever is driving
+// the download and the session so they can be changed while it runs.
+#[derive(Debug)]
+pub struct FilePriorities {
+    files: Mutex<Vec<FilePriority>>,
+    // The files each piece overlaps, pieces may straddle several.
+    piece_files: Vec<Range<usize>>,
+    changed: Notify,
+}
+
+impl FilePriorities {
+    pub(crate) fn new(torrent_file: &TorrentFile) -> Self {
+        let piece_files = (0..torrent_file.piece_count)
+            .map(|index| {
+                let slices = torrent_file.file_slices_for_piece(index);
+                let first = slices.first().map_or(0, |slice| slice.file_index);
+                let last = slices.last().map_or(0, |slice| slice.file_index + 1);
+                first..last
+            })
+            .collect();
+        FilePriorities {
+            files: Mutex::new(vec![FilePriority::Normal; torrent_file.files().len()]),
+            piece_files,
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn set(&self, file_index: usize, priority: FilePriority) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .get_mut(file_index)
+            .ok_or_else(|| anyhow!("no file with index {}", file_index))?;
+        if *file != priority {
+            *file = priority;
+            self.changed.notify_one();
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, file_index: usize) -> Option<FilePriority> {
+        self.files.lock().unwrap().get(file_index).copied()
+    }
+
+    pub fn to_vec(&self) -> Vec<FilePriority> {
+        self.files.lock().unwrap().clone()
+    }
+
+    // A piece is worth as much as the most wanted file it's part of, so
+    // pieces shared with a wanted file are still fetched when their other
+    // files are skipped.
+    pub(crate) fn piece_priority(&self, index: PieceIndex) -> FilePriority {
+        let files = self.files.lock().unwrap();
+        files[self.piece_files[index].clone()]
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(FilePriority::Skip)
+    }
+
+    // Resolves once any priority has changed since the last call.
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await
+    }
+}
@@ -0,0 +1,171 @@
+use super::storage::{FileStat, Storage};
+use super::torrent::TorrentFile;
+use super::types::{Bitfield, InfoHash};
+use super::verify::{self, PieceStatus};
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+const FILE_FORMAT: &str = "leech resume file";
+
+// What we knew about a torrent's data when we last stopped, along the lines
+// of libtorrent's fast-resume files.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ResumeData {
+    #[serde(rename = "file-format")]
+    file_format: String,
+    #[serde(rename = "info-hash")]
+    info_hash: Bytes,
+    // Verified pieces, laid out like the payload of a bitfield message.
+    pieces: Bytes,
+    // Every file's length and mtime when the resume data was written.
+    files: Vec<FileStat>,
+}
+
+// Records which pieces we have, along with the current state of the files
+// they're stored in.
+pub(crate) fn save(
+    storage: &dyn Storage,
+    info_hash: InfoHash,
+    own_pieces: &Bitfield,
+) -> Result<()> {
+    let resume = ResumeData {
+        file_format: String::from(FILE_FORMAT),
+        info_hash: Bytes::copy_from_slice(&info_hash),
+        pieces: Bytes::copy_from_slice(own_pieces.as_raw_slice()),
+        files: storage.file_stats()?,
+    };
+    storage.write_resume(&serde_bencode::to_bytes(&resume)?)
+}
+
+// Works out which pieces we already have.  Resume data is trusted if it is
+// for this torrent and no file has changed length or mtime since it was
+// written, otherwise every piece is re-hashed.
+pub(crate) async fn own_pieces(
+    torrent_file: &TorrentFile,
+    storage: &Arc<dyn Storage>,
+) -> Result<Bitfield> {
+    if let Some(pieces) = trusted_pieces(torrent_file, storage.as_ref())? {
+        return Ok(pieces);
+    }
+    println!("checking existing data for {}", torrent_file.info.name);
+    let statuses = verify::check_pieces(torrent_file, storage.clone()).await?;
+    Ok(statuses
+        .iter()
+        .map(|status| *status == PieceStatus::Complete)
+        .collect())
+}
+
+fn trusted_pieces(torrent_file: &TorrentFile, storage: &dyn Storage) -> Result<Option<Bitfield>> {
+    let data = match storage.read_resume()? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let resume: ResumeData = match serde_bencode::from_bytes(&data) {
+        Ok(resume) => resume,
+        Err(e) => {
+            println!("ignoring unreadable resume data: {}", e);
+            return Ok(None);
+        }
+    };
+    let expected_len = torrent_file.piece_count.div_ceil(8);
+    if resume.file_format != FILE_FORMAT
+        || resume.info_hash[..] != torrent_file.info.info_hash
+        || resume.pieces.len() != expected_len
+    {
+        println!("ignoring resume data for a different torrent");
+        return Ok(None);
+    }
+    if resume.files != storage.file_stats()? {
+        println!("files have changed since the resume data was written");
+        return Ok(None);
+    }
+    let mut pieces = Bitfield::from_vec(resume.pieces.to_vec());
+    pieces.truncate(torrent_file.piece_count);
+    Ok(Some(pieces))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::storage::{FileStorage, MemoryStorage};
+    use crate::client::torrent::test_helpers::bitfield;
+
+    fn torrent() -> TorrentFile {
+        TorrentFile::for_tests(16, &[&[1; 20], &[2; 12]])
+    }
+
+    #[test]
+    fn trusted_when_nothing_changed() {
+        let torrent = torrent();
+        let storage = MemoryStorage::new();
+        storage.open(&torrent).unwrap();
+        assert!(trusted_pieces(&torrent, &storage).unwrap().is_none());
+        let pieces = bitfield(&[true, false]);
+        save(&storage, torrent.info.info_hash, &pieces).unwrap();
+        assert_eq!(trusted_pieces(&torrent, &storage).unwrap(), Some(pieces));
+    }
+
+    #[test]
+    fn ignored_for_another_torrent() {
+        let torrent = torrent();
+        let storage = MemoryStorage::new();
+        storage.open(&torrent).unwrap();
+        save(&storage, [0; 20], &bitfield(&[true, true])).unwrap();
+        assert!(trusted_pieces(&torrent, &storage).unwrap().is_none());
+        // the right torrent, but the wrong number of pieces
+        save(&storage, torrent.info.info_hash, &bitfield(&[true; 9])).unwrap();
+        assert!(trusted_pieces(&torrent, &storage).unwrap().is_none());
+    }
+
+    #[test]
+    fn ignored_when_unreadable() {
+        let torrent = torrent();
+        let storage = MemoryStorage::new();
+        storage.open(&torrent).unwrap();
+        storage.write_resume(b"not bencode").unwrap();
+        assert!(trusted_pieces(&torrent, &storage).unwrap().is_none());
+    }
+
+    #[test]
+    fn ignored_once_a_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = torrent();
+        let storage = FileStorage::new(dir.path());
+        storage.open(&torrent).unwrap();
+        storage.write(0, 0, &[1; 16]).unwrap();
+        save(&storage, torrent.info.info_hash, &bitfield(&[true, false])).unwrap();
+        assert!(trusted_pieces(&torrent, &storage).unwrap().is_some());
+
+        std::fs::write(dir.path().join("test/f1"), b"changed").unwrap();
+        assert!(trusted_pieces(&torrent, &storage).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn pieces_are_checked_without_resume_data() {
+        let torrent = torrent();
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        storage.open(&torrent).unwrap();
+        storage.write(0, 0, &[1; 16]).unwrap();
+        // wrong data for the second piece
+        storage.write(1, 0, &[0; 16]).unwrap();
+        assert_eq!(
+            own_pieces(&torrent, &storage).await.unwrap(),
+            bitfield(&[true, false])
+        );
+
+        // resume data is believed over the data itself
+        save(
+            storage.as_ref(),
+            torrent.info.info_hash,
+            &bitfield(&[true, true]),
+        )
+        .unwrap();
+        assert_eq!(
+            own_pieces(&torrent, &storage).await.unwrap(),
+            bitfield(&[true, true])
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use super::piece_picker::PiecePicker;
+use super::types::{Bitfield, PieceIndex};
+use super::PieceWork;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::task;
+
+// Download workers are told apart by the id of the task they run in, so the
+// session can tell whose work to take back when one exits.
+pub(crate) type WorkerId = task::Id;
+
+// At most this many peers are sent the same piece.  Once there is nothing
+// fresh left to hand out, a piece already in flight may go to a second peer
+// so a single slow one can't hold up the end of the download.
+const MAX_PEERS_PER_PIECE: usize = 2;
+
+// What download workers tell the session.
+#[derive(Debug)]
+pub(crate) enum WorkerEvent {
+    // The worker's peer's bitfield.  Sent empty when the worker connects,
+    // and again if the peer turns out to have pieces.
+    Pieces(WorkerId, Bitfield),
+    Have(WorkerId, PieceIndex),
+    // Asks for a piece the peer has.  The reply waits until there is one,
+    // and the sender is dropped once there's nothing left to download.
+    Request(WorkerId, oneshot::Sender<PieceWork>),
+    // The worker has given up on its piece, e.g. because it was choked.
+    Release(WorkerId, PieceIndex),
+}
+
+#[derive(Debug, Default)]
+struct WorkerState {
+    pieces: Bitfield,
+    // The piece the worker is downloading, workers fetch one at a time.
+    piece: Option<PieceIndex>,
+    waiting: Option<oneshot::Sender<PieceWork>>,
+}
+
+// Decides which worker downloads what.  Owned by the session, which feeds it
+// the workers' events along with the results of hashing.
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    picker: PiecePicker,
+    // Every piece of the torrent, by index.
+    pieces: Arc<Vec<PieceWork>>,
+    workers: HashMap<WorkerId, WorkerState>,
+    // The workers each piece has been handed to, from when it's handed out
+    // until it has been hashed or given up on.
+    in_flight: HashMap<PieceIndex, Vec<WorkerId>>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(picker: PiecePicker, pieces: Arc<Vec<PieceWork>>) -> Self {
+        Scheduler {
+            picker,
+            pieces,
+            workers: HashMap::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn own_pieces(&self) -> &Bitfield {
+        self.picker.own_pieces()
+    }
+
+    pub(crate) fn wanted_count(&self) -> usize {
+        self.picker.wanted_count()
+    }
+
+    pub(crate) fn wanted_pieces(&self) -> Bitfield {
+        self.picker.wanted_pieces()
+    }
+
+    pub(crate) fn handle(&mut self, event: WorkerEvent) {
+        match event {
+            WorkerEvent::Pieces(worker, pieces) => {
+                self.picker.register_peer_pieces(&pieces);
+                let state = self.workers.entry(worker).or_default();
+                let old = std::mem::replace(&mut state.pieces, pieces);
+                self.picker.unregister_peer_pieces(&old);
+                self.assign(worker);
+            }
+            WorkerEvent::Have(worker, index) => {
+                if let Some(state) = self.workers.get_mut(&worker) {
+                    if index < state.pieces.len() && !state.pieces[index] {
+                        state.pieces.set(index, true);
+                        self.picker.register_peer_piece(index);
+                        self.assign(worker);
+                    }
+                }
+            }
+            WorkerEvent::Request(worker, reply) => {
+                // whatever it had before has been handed over for hashing
+                let state = self.workers.entry(worker).or_default();
+                state.piece = None;
+                // dropping the reply tells the worker we're done
+                if self.picker.wanted_count() > 0 {
+                    state.waiting = Some(reply);
+                    self.assign(worker);
+                }
+            }
+            WorkerEvent::Release(worker, index) => {
+                if let Some(state) = self.workers.get_mut(&worker) {
+                    state.piece = None;
+                }
+                self.release(worker, index);
+                self.assign_waiting();
+            }
+        }
+    }
+
+    // Takes back everything a worker had, once its task has finished.
+    // Pieces it already downloaded are left until they've been hashed.
+    pub(crate) fn remove_worker(&mut self, worker: WorkerId) {
+        if let Some(state) = self.workers.remove(&worker) {
+            self.picker.unregister_peer_pieces(&state.pieces);
+            if let Some(index) = state.piece {
+                self.release(worker, index);
+                self.assign_waiting();
+            }
+        }
+    }
+
+    pub(crate) fn piece_hashed(&mut self, worker: WorkerId, index: PieceIndex, verified: bool) {
+        if verified {
+            // anyone else still fetching it will be wasting their time, but
+            // there's no telling them
+            self.in_flight.remove(&index);
+            self.picker.received_piece(index);
+        } else {
+            self.release(worker, index);
+            self.assign_waiting();
+        }
+    }
+
+    // Lets every waiting worker know there's nothing left to download.
+    pub(crate) fn finish(&mut self) {
+        for state in self.workers.values_mut() {
+            state.waiting = None;
+        }
+    }
+
+    // Pieces that have just become wanted may suit a waiting worker.
+    pub(crate) fn priorities_changed(&mut self) {
+        self.picker.priorities_changed();
+        self.assign_waiting();
+    }
+
+    // Tries again for every worker still waiting for a piece, something may
+    // have been freed up or become wanted.
+    pub(crate) fn assign_waiting(&mut self) {
+        let waiting: Vec<WorkerId> = self
+            .workers
+            .iter()
+            .filter(|(_, state)| state.waiting.is_some())
+            .map(|(worker, _)| *worker)
+            .collect();
+        for worker in waiting {
+            self.assign(worker);
+        }
+    }
+
+    // The piece can be picked again once nobody else has it.
+    fn release(&mut self, worker: WorkerId, index: PieceIndex) {
+        if let Some(fetching) = self.in_flight.get_mut(&index) {
+            fetching.retain(|other| *other != worker);
+            if !fetching.is_empty() {
+                return;
+            }
+            self.in_flight.remove(&index);
+        }
+        self.picker.unpick_piece(index);
+    }
+
+    fn assign(&mut self, worker: WorkerId) {
+        let peer_pieces = match self.workers.get(&worker) {
+            Some(state) if state.waiting.is_some() => state.pieces.clone(),
+            _ => return,
+        };
+        let index = match self.pick(worker, &peer_pieces) {
+            Some(index) => index,
+            None => return,
+        };
+        self.in_flight.entry(index).or_default().push(worker);
+        let state = self.workers.get_mut(&worker).unwrap();
+        let reply = state.waiting.take().unwrap();
+        if reply.send(self.pieces[index]).is_ok() {
+            state.piece = Some(index);
+        } else {
+            // the worker has gone, it's removed once its task is joined
+            self.release(worker, index);
+        }
+    }
+
+    fn pick(&mut self, worker: WorkerId, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        if let Some(index) = self.picker.pick_piece(peer_pieces) {
+            return Some(index);
+        }
+        // nothing fresh, help out with the piece fewest peers are on
+        self.in_flight
+            .iter()
+            .filter(|(index, fetching)| {
+                fetching.len() < MAX_PEERS_PER_PIECE
+                    && !fetching.contains(&worker)
+                    && peer_pieces.get(**index).is_some_and(|bit| *bit)
+                    && self.picker.is_wanted(**index)
+            })
+            .min_by_key(|(_, fetching)| fetching.len())
+            .map(|(index, _)| *index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::priority::FilePriorities;
+    use crate::client::torrent::test_helpers::{bitfield, workers};
+    use crate::client::torrent::TorrentFile;
+    use tokio::sync::oneshot::error::TryRecvError;
+
+    // A scheduler for `piece_count` pieces, none of which we have.
+    fn scheduler(piece_count: usize) -> Scheduler {
+        let files = vec![[0; 16]; piece_count];
+        let files: Vec<&[u8]> = files.iter().map(|file| &file[..]).collect();
+        let torrent = TorrentFile::for_tests(16, &files);
+        let priorities = Arc::new(FilePriorities::new(&torrent));
+        let picker = PiecePicker::new(Bitfield::repeat(false, piece_count), priorities);
+        Scheduler::new(picker, Arc::new(PieceWork::all(&torrent)))
+    }
+
+    fn request(scheduler: &mut Scheduler, worker: WorkerId) -> oneshot::Receiver<PieceWork> {
+        let (tx, rx) = oneshot::channel();
+        scheduler.handle(WorkerEvent::Request(worker, tx));
+        rx
+    }
+
+    fn assigned(rx: &mut oneshot::Receiver<PieceWork>) -> Option<PieceIndex> {
+        rx.try_recv().ok().map(|work| work.index)
+    }
+
+    #[tokio::test]
+    async fn waits_until_the_peer_has_something() {
+        let mut scheduler = scheduler(2);
+        let worker = workers(1).await[0];
+        scheduler.handle(WorkerEvent::Pieces(worker, bitfield(&[false, false])));
+        let mut rx = request(&mut scheduler, worker);
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+        scheduler.handle(WorkerEvent::Have(worker, 1));
+        assert_eq!(assigned(&mut rx), Some(1));
+    }
+
+    #[tokio::test]
+    async fn released_pieces_go_to_waiting_workers() {
+        let mut scheduler = scheduler(1);
+        let [first, second] = workers(2).await[..] else {
+            unreachable!()
+        };
+        for worker in [first, second] {
+            scheduler.handle(WorkerEvent::Pieces(worker, bitfield(&[true])));
+        }
+        let mut first_rx = request(&mut scheduler, first);
+        assert_eq!(assigned(&mut first_rx), Some(0));
+        // the only piece is taken, but a second peer may help with it
+        let mut second_rx = request(&mut scheduler, second);
+        assert_eq!(assigned(&mut second_rx), Some(0));
+
+        // both give up, a fresh request gets it back
+        scheduler.handle(WorkerEvent::Release(first, 0));
+        scheduler.handle(WorkerEvent::Release(second, 0));
+        let mut rx = request(&mut scheduler, first);
+        assert_eq!(assigned(&mut rx), Some(0));
+    }
+
+    #[tokio::test]
+    async fn at_most_two_peers_per_piece() {
+        let mut scheduler = scheduler(1);
+        let ids = workers(3).await;
+        let mut receivers: Vec<_> = ids
+            .iter()
+            .map(|worker| {
+                scheduler.handle(WorkerEvent::Pieces(*worker, bitfield(&[true])));
+                request(&mut scheduler, *worker)
+            })
+            .collect();
+        assert_eq!(assigned(&mut receivers[0]), Some(0));
+        assert_eq!(assigned(&mut receivers[1]), Some(0));
+        assert_eq!(assigned(&mut receivers[2]), None);
+
+        // the third worker steps in when one of the others leaves
+        scheduler.remove_worker(ids[0]);
+        assert_eq!(assigned(&mut receivers[2]), Some(0));
+    }
+
+    #[tokio::test]
+    async fn departed_workers_pieces_are_reclaimed() {
+        let mut scheduler = scheduler(2);
+        let [first, second] = workers(2).await[..] else {
+            unreachable!()
+        };
+        scheduler.handle(WorkerEvent::Pieces(first, bitfield(&[true, false])));
+        scheduler.handle(WorkerEvent::Pieces(second, bitfield(&[true, true])));
+        let mut first_rx = request(&mut scheduler, first);
+        assert_eq!(assigned(&mut first_rx), Some(0));
+        let mut second_rx = request(&mut scheduler, second);
+        assert_eq!(assigned(&mut second_rx), Some(1));
+
+        scheduler.remove_worker(first);
+        scheduler.piece_hashed(second, 1, true);
+        let mut second_rx = request(&mut scheduler, second);
+        assert_eq!(assigned(&mut second_rx), Some(0));
+    }
+
+    #[tokio::test]
+    async fn failed_pieces_are_fetched_again() {
+        let mut scheduler = scheduler(1);
+        let worker = workers(1).await[0];
+        scheduler.handle(WorkerEvent::Pieces(worker, bitfield(&[true])));
+        let mut rx = request(&mut scheduler, worker);
+        assert_eq!(assigned(&mut rx), Some(0));
+        let mut rx = request(&mut scheduler, worker);
+        assert_eq!(assigned(&mut rx), None);
+        scheduler.piece_hashed(worker, 0, false);
+        assert_eq!(assigned(&mut rx), Some(0));
+    }
+
+    #[tokio::test]
+    async fn done_once_nothing_is_wanted() {
+        let mut scheduler = scheduler(2);
+        let [first, second] = workers(2).await[..] else {
+            unreachable!()
+        };
+        scheduler.handle(WorkerEvent::Pieces(first, bitfield(&[true, false])));
+        scheduler.handle(WorkerEvent::Pieces(second, bitfield(&[true, true])));
+        let mut first_rx = request(&mut scheduler, first);
+        let mut second_rx = request(&mut scheduler, second);
+        scheduler.piece_hashed(first, assigned(&mut first_rx).unwrap(), true);
+        scheduler.piece_hashed(second, assigned(&mut second_rx).unwrap(), true);
+        assert_eq!(scheduler.wanted_count(), 0);
+
+        // requests made now are answered by dropping the reply
+        let mut rx = request(&mut scheduler, first);
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Closed);
+    }
+
+    #[tokio::test]
+    async fn finish_drops_waiting_workers() {
+        let mut scheduler = scheduler(1);
+        let worker = workers(1).await[0];
+        scheduler.handle(WorkerEvent::Pieces(worker, bitfield(&[false])));
+        let mut rx = request(&mut scheduler, worker);
+        scheduler.finish();
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Closed);
+    }
+}
@@ -0,0 +1,565 @@
+use super::partfile::PartFile;
+use super::priority::FilePriority;
+use super::torrent::{self, FileInfo, FileSlice, TorrentFile};
+use super::types::PieceIndex;
+
+use anyhow::{anyhow, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+// Where downloaded pieces end up.  Everything is addressed by piece index
+// and an offset into that piece, backends that care about files can use the
+// torrent's file table to work out where those bytes belong.
+//
+// Calls block, the session runs them on tokio's blocking pool.
+pub trait Storage: Send + Sync + fmt::Debug {
+    // Called once before anything is read or written, with the torrent
+    // whose data will be stored.
+    fn open(&self, torrent_file: &TorrentFile) -> Result<()>;
+    fn write(&self, index: PieceIndex, offset: usize, data: &[u8]) -> Result<()>;
+    // Fails if any of the range was never stored.
+    fn read(&self, index: PieceIndex, offset: usize, length: usize) -> Result<Vec<u8>>;
+    // Makes sure everything written so far is durable.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    // Length and modification time of each file, for backends that keep
+    // the torrent in files.  Resume data records these so that changes made
+    // behind our back are noticed.
+    fn file_stats(&self) -> Result<Vec<FileStat>> {
+        Ok(Vec::new())
+    }
+    // Resume data is kept with the torrent's data.  Backends that can't
+    // store it have every piece re-checked when the torrent is opened.
+    fn read_resume(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    fn write_resume(&self, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+    // Called before the download starts and whenever a priority changes.
+    // Skipped files shouldn't be created, backends that care have to keep
+    // the parts of pieces that fall in them somewhere else.
+    fn set_file_priorities(&self, _priorities: &[FilePriority]) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    pub length: u64,
+    // seconds since the unix epoch
+    pub mtime: u64,
+}
+
+// Where a piece's bytes sit in the torrent as a whole, as if every file were
+// concatenated together in order.
+#[derive(Debug, Clone)]
+struct Layout {
+    piece_length: usize,
+    length: usize,
+    files: Vec<FileInfo>,
+}
+
+impl Layout {
+    fn new(torrent_file: &TorrentFile) -> Self {
+        Layout {
+            piece_length: torrent_file.info.piece_length,
+            length: torrent_file.info.length,
+            files: torrent_file.files().to_vec(),
+        }
+    }
+
+    fn range(&self, index: PieceIndex, offset: usize, length: usize) -> Result<(usize, usize)> {
+        let start = index * self.piece_length + offset;
+        let end = start + length;
+        if offset + length > self.piece_length || end > self.length {
+            return Err(anyhow!(
+                "{} bytes at offset {} of piece {} are outside the torrent",
+                length,
+                offset,
+                index
+            ));
+        }
+        Ok((start, end))
+    }
+
+    // A piece can straddle the boundary between two or more files, this
+    // returns the part of the range that lands in each of them.
+    fn slices(&self, index: PieceIndex, offset: usize, length: usize) -> Result<Vec<FileSlice>> {
+        let (start, end) = self.range(index, offset, length)?;
+        Ok(torrent::file_slices(&self.files, start, end))
+    }
+}
+
+// Writes pieces straight into the torrent's files under `root`.  Multi-file
+// torrents end up in a directory named after the torrent, single file
+// torrents in a file of that name.  Resume data goes next to them, in
+// `<name>.resume`, and the parts of pieces that fall in skipped files in
+// `<name>.parts`.
+#[derive(Debug)]
+pub struct FileStorage {
+    root: PathBuf,
+    read_only: bool,
+    open: Mutex<Option<OpenFiles>>,
+}
+
+#[derive(Debug)]
+struct OpenFiles {
+    layout: Layout,
+    paths: Vec<PathBuf>,
+    // Files are created the first time they're written to, `None` until
+    // then.
+    handles: Vec<Option<File>>,
+    skipped: Vec<bool>,
+    part_file: PartFile,
+    resume_path: PathBuf,
+}
+
+impl OpenFiles {
+    fn handle(&mut self, file_index: usize) -> Result<&mut File> {
+        if self.handles[file_index].is_none() {
+            let path = &self.paths[file_index];
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            self.handles[file_index] = Some(handle);
+        }
+        Ok(self.handles[file_index].as_mut().unwrap())
+    }
+
+    // Pieces that touch a skipped file go to the partfile whole, so any of
+    // their files can be filled in from it later.
+    fn write(&mut self, index: PieceIndex, offset: usize, data: &[u8]) -> Result<()> {
+        let slices = self.layout.slices(index, offset, data.len())?;
+        if slices.iter().any(|slice| self.skipped[slice.file_index]) {
+            self.part_file.write(index, offset, data)?;
+        }
+        for slice in slices {
+            if self.skipped[slice.file_index] {
+                continue;
+            }
+            let handle = self.handle(slice.file_index)?;
+            handle.seek(SeekFrom::Start(slice.file_offset as u64))?;
+            handle.write_all(&data[slice.piece_offset..slice.piece_offset + slice.length])?;
+        }
+        Ok(())
+    }
+
+    // Data for skipped files comes from the partfile when it's there, it may
+    // also still be in the file itself if the file was skipped after it was
+    // written.
+    fn read(&mut self, index: PieceIndex, offset: usize, length: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        for slice in self.layout.slices(index, offset, length)? {
+            let buf = &mut data[slice.piece_offset..slice.piece_offset + slice.length];
+            let in_part_file = self.part_file.has(index)
+                && (self.skipped[slice.file_index] || self.handles[slice.file_index].is_none());
+            if in_part_file {
+                self.part_file
+                    .read(index, offset + slice.piece_offset, buf)?;
+                continue;
+            }
+            let handle = self.handles[slice.file_index].as_mut().ok_or_else(|| {
+                anyhow!("{} does not exist", self.paths[slice.file_index].display())
+            })?;
+            handle.seek(SeekFrom::Start(slice.file_offset as u64))?;
+            handle.read_exact(buf)?;
+        }
+        Ok(data)
+    }
+}
+
+impl FileStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        FileStorage {
+            root: root.into(),
+            read_only: false,
+            open: Mutex::new(None),
+        }
+    }
+
+    // For looking at data that's already there without changing anything,
+    // nothing is created and every write fails.
+    pub fn read_only<P: Into<PathBuf>>(root: P) -> Self {
+        FileStorage {
+            read_only: true,
+            ..FileStorage::new(root)
+        }
+    }
+
+    fn with_open<T>(&self, f: impl FnOnce(&mut OpenFiles) -> Result<T>) -> Result<T> {
+        let mut open = self.open.lock().unwrap();
+        let open = open
+            .as_mut()
+            .ok_or_else(|| anyhow!("storage is not open"))?;
+        f(open)
+    }
+}
+
+impl Storage for FileStorage {
+    // Picks up whichever of the torrent's files already exist, so data from
+    // an earlier run can be used again.  Nothing is created until it's
+    // written, and files grow as pieces are written to them, so data past
+    // the end of a file has never been written.
+    fn open(&self, torrent_file: &TorrentFile) -> Result<()> {
+        let layout = Layout::new(torrent_file);
+        let mut paths = Vec::with_capacity(layout.files.len());
+        let mut handles = Vec::with_capacity(layout.files.len());
+        for file in &layout.files {
+            let path = self.root.join(&file.path);
+            let handle = if self.read_only {
+                File::open(&path).ok()
+            } else {
+                OpenOptions::new().read(true).write(true).open(&path).ok()
+            };
+            paths.push(path);
+            handles.push(handle);
+        }
+        let name = &torrent_file.info.name;
+        let part_file = PartFile::open(
+            self.root.join(format!("{}.parts", name)),
+            torrent_file.piece_count,
+            torrent_file.info.piece_length,
+            self.read_only,
+        )?;
+        *self.open.lock().unwrap() = Some(OpenFiles {
+            skipped: vec![false; layout.files.len()],
+            layout,
+            paths,
+            handles,
+            part_file,
+            resume_path: self.root.join(format!("{}.resume", name)),
+        });
+        Ok(())
+    }
+
+    fn write(&self, index: PieceIndex, offset: usize, data: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!("storage is read only"));
+        }
+        self.with_open(|open| open.write(index, offset, data))
+    }
+
+    fn read(&self, index: PieceIndex, offset: usize, length: usize) -> Result<Vec<u8>> {
+        self.with_open(|open| open.read(index, offset, length))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.with_open(|open| {
+            for handle in open.handles.iter().flatten() {
+                handle.sync_all()?;
+            }
+            open.part_file.sync()
+        })
+    }
+
+    // Wanted files that are empty never get written to, they're created
+    // here instead.  Wanted files also get their share of any piece in the
+    // partfile, they may have been skipped when it was written, possibly
+    // during an earlier run.
+    fn set_file_priorities(&self, priorities: &[FilePriority]) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.with_open(|open| {
+            for (file_index, priority) in priorities.iter().enumerate() {
+                open.skipped[file_index] = *priority == FilePriority::Skip;
+                if open.skipped[file_index] {
+                    continue;
+                }
+                if open.layout.files[file_index].length == 0 {
+                    open.handle(file_index)?;
+                }
+                let file = open.layout.files[file_index].clone();
+                let piece_length = open.layout.piece_length;
+                let first = file.offset / piece_length;
+                let last = (file.offset + file.length).div_ceil(piece_length);
+                for index in first..last {
+                    if !open.part_file.has(index) {
+                        continue;
+                    }
+                    let piece_start = index * piece_length;
+                    let start = std::cmp::max(file.offset, piece_start);
+                    let end = std::cmp::min(file.offset + file.length, piece_start + piece_length);
+                    let mut buf = vec![0; end - start];
+                    open.part_file.read(index, start - piece_start, &mut buf)?;
+                    let handle = open.handle(file_index)?;
+                    handle.seek(SeekFrom::Start((start - file.offset) as u64))?;
+                    handle.write_all(&buf)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Files that don't exist yet count as empty, and the partfile is
+    // included after the torrent's own files.
+    fn file_stats(&self) -> Result<Vec<FileStat>> {
+        self.with_open(|open| {
+            open.paths
+                .iter()
+                .chain(std::iter::once(open.part_file.path()))
+                .map(|path| match fs::metadata(path) {
+                    Ok(metadata) => {
+                        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+                        Ok(FileStat {
+                            length: metadata.len(),
+                            mtime: mtime.as_secs(),
+                        })
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(FileStat {
+                        length: 0,
+                        mtime: 0,
+                    }),
+                    Err(e) => Err(e.into()),
+                })
+                .collect()
+        })
+    }
+
+    fn read_resume(&self) -> Result<Option<Vec<u8>>> {
+        self.with_open(|open| match fs::read(&open.resume_path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    // Written to a temporary file first so that being killed part way
+    // through never leaves a truncated resume file behind.
+    fn write_resume(&self, data: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!("storage is read only"));
+        }
+        self.with_open(|open| {
+            let tmp = open.resume_path.with_extension("resume.tmp");
+            fs::write(&tmp, data)?;
+            fs::rename(&tmp, &open.resume_path)?;
+            Ok(())
+        })
+    }
+}
+
+// Keeps the whole torrent in memory, handy for tests and small torrents.
+// The buffer grows as pieces are written.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    open: Mutex<Option<(Layout, Vec<u8>)>>,
+    resume: Mutex<Option<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, torrent_file: &TorrentFile) -> Result<()> {
+        let layout = Layout::new(torrent_file);
+        *self.open.lock().unwrap() = Some((layout, Vec::new()));
+        Ok(())
+    }
+
+    fn write(&self, index: PieceIndex, offset: usize, data: &[u8]) -> Result<()> {
+        let mut open = self.open.lock().unwrap();
+        let (layout, buffer) = open
+            .as_mut()
+            .ok_or_else(|| anyhow!("storage is not open"))?;
+        let (start, end) = layout.range(index, offset, data.len())?;
+        if buffer.len() < end {
+            buffer.resize(end, 0);
+        }
+        buffer[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, index: PieceIndex, offset: usize, length: usize) -> Result<Vec<u8>> {
+        let open = self.open.lock().unwrap();
+        let (layout, buffer) = open
+            .as_ref()
+            .ok_or_else(|| anyhow!("storage is not open"))?;
+        let (start, end) = layout.range(index, offset, length)?;
+        if buffer.len() < end {
+            return Err(anyhow!("piece {} has not been written", index));
+        }
+        Ok(buffer[start..end].to_vec())
+    }
+
+    fn read_resume(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.resume.lock().unwrap().clone())
+    }
+
+    fn write_resume(&self, data: &[u8]) -> Result<()> {
+        *self.resume.lock().unwrap() = Some(data.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECE_LENGTH: usize = 16;
+
+    // Two files, the second piece straddles them.
+    fn torrent() -> TorrentFile {
+        TorrentFile::for_tests(PIECE_LENGTH, &[&[1; 20], &[2; 12]])
+    }
+
+    fn piece(torrent: &TorrentFile, index: PieceIndex) -> Vec<u8> {
+        let mut data = vec![1; 20];
+        data.extend([2; 12]);
+        let (start, end) = torrent.calculate_bounds_for_piece(index);
+        data[start..end].to_vec()
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let torrent = torrent();
+        let storage = MemoryStorage::new();
+        assert!(storage.read(0, 0, 1).is_err());
+        storage.open(&torrent).unwrap();
+        storage.write(1, 0, &piece(&torrent, 1)).unwrap();
+        assert_eq!(storage.read(1, 4, 8).unwrap(), piece(&torrent, 1)[4..12]);
+        // the buffer has grown past piece 0, which reads back as zeroes
+        assert!(storage.read(0, 0, 16).is_ok());
+        assert!(storage.read(1, 0, 17).is_err());
+        assert!(storage.read(2, 0, 16).is_err());
+        assert!(storage.write(1, 8, &[0; 9]).is_err());
+        assert!(storage.write(2, 0, &[0; 1]).is_err());
+    }
+
+    #[test]
+    fn memory_resume() {
+        let storage = MemoryStorage::new();
+        assert_eq!(storage.read_resume().unwrap(), None);
+        storage.write_resume(b"resume").unwrap();
+        assert_eq!(storage.read_resume().unwrap(), Some(b"resume".to_vec()));
+    }
+
+    #[test]
+    fn files_are_written_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = torrent();
+        let storage = FileStorage::new(dir.path());
+        storage.open(&torrent).unwrap();
+        assert!(!dir.path().join("test").exists());
+        storage.write(1, 0, &piece(&torrent, 1)).unwrap();
+        storage.write(0, 0, &piece(&torrent, 0)).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(fs::read(dir.path().join("test/f0")).unwrap(), [1; 20]);
+        assert_eq!(fs::read(dir.path().join("test/f1")).unwrap(), [2; 12]);
+        assert_eq!(storage.read(1, 2, 4).unwrap(), [1, 1, 2, 2]);
+
+        // picked up again when reopened
+        let storage = FileStorage::new(dir.path());
+        storage.open(&torrent).unwrap();
+        assert_eq!(storage.read(1, 0, 16).unwrap(), piece(&torrent, 1));
+    }
+
+    #[test]
+    fn missing_files_cannot_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path());
+        assert!(storage.read(0, 0, 1).is_err());
+        storage.open(&torrent()).unwrap();
+        assert!(storage.read(0, 0, 1).is_err());
+    }
+
+    #[test]
+    fn file_stats_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = torrent();
+        let storage = FileStorage::new(dir.path());
+        storage.open(&torrent).unwrap();
+        let empty = FileStat {
+            length: 0,
+            mtime: 0,
+        };
+        // both files and the partfile
+        assert_eq!(storage.file_stats().unwrap(), vec![empty; 3]);
+        storage.write(0, 0, &piece(&torrent, 0)).unwrap();
+        assert_eq!(storage.file_stats().unwrap()[0].length, 16);
+
+        assert_eq!(storage.read_resume().unwrap(), None);
+        storage.write_resume(b"resume").unwrap();
+        assert_eq!(storage.read_resume().unwrap(), Some(b"resume".to_vec()));
+        assert!(dir.path().join("test.resume").exists());
+    }
+
+    #[test]
+    fn read_only_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = torrent();
+        let storage = FileStorage::new(dir.path());
+        storage.open(&torrent).unwrap();
+        storage.write(0, 0, &piece(&torrent, 0)).unwrap();
+
+        let storage = FileStorage::read_only(dir.path());
+        storage.open(&torrent).unwrap();
+        assert_eq!(storage.read(0, 0, 16).unwrap(), piece(&torrent, 0));
+        assert!(storage.write(0, 0, &[0; 16]).is_err());
+        assert!(storage.write_resume(b"resume").is_err());
+        storage
+            .set_file_priorities(&[FilePriority::Normal, FilePriority::Normal])
+            .unwrap();
+        assert!(!dir.path().join("test/f1").exists());
+    }
+
+    #[test]
+    fn skipped_files_go_to_the_partfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = torrent();
+        let storage = FileStorage::new(dir.path());
+        storage.open(&torrent).unwrap();
+        storage
+            .set_file_priorities(&[FilePriority::Normal, FilePriority::Skip])
+            .unwrap();
+        storage.write(0, 0, &piece(&torrent, 0)).unwrap();
+        storage.write(1, 0, &piece(&torrent, 1)).unwrap();
+        assert!(!dir.path().join("test/f1").exists());
+        assert!(dir.path().join("test.parts").exists());
+        assert_eq!(fs::read(dir.path().join("test/f0")).unwrap(), [1; 20]);
+        assert_eq!(storage.read(1, 0, 16).unwrap(), piece(&torrent, 1));
+
+        // the skipped file's share is still there after a restart
+        let storage = FileStorage::new(dir.path());
+        storage.open(&torrent).unwrap();
+        storage
+            .set_file_priorities(&[FilePriority::Normal, FilePriority::Skip])
+            .unwrap();
+        assert_eq!(storage.read(1, 0, 16).unwrap(), piece(&torrent, 1));
+
+        // and fills the file in once it's wanted
+        storage
+            .set_file_priorities(&[FilePriority::Normal, FilePriority::Normal])
+            .unwrap();
+        assert_eq!(fs::read(dir.path().join("test/f1")).unwrap(), [2; 12]);
+    }
+
+    #[test]
+    fn empty_files_are_created_when_wanted() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = TorrentFile::for_tests(PIECE_LENGTH, &[&[1; 16], &[]]);
+        let storage = FileStorage::new(dir.path());
+        storage.open(&torrent).unwrap();
+        storage
+            .set_file_priorities(&[FilePriority::Skip, FilePriority::Normal])
+            .unwrap();
+        assert!(dir.path().join("test/f1").exists());
+        assert!(!dir.path().join("test/f0").exists());
+    }
+}
@@ -6,98 +6,335 @@ use serde_bencode::de;
 
 use bytes::Bytes;
 
-use std::io::Read;
-use std::{convert::TryInto, fs};
+use std::path::{Path, PathBuf};
+use std::{convert::TryInto, error, fmt, fs, io};
 
+use super::bencode;
 use super::types::{InfoHash, PieceHashes};
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct BencodeFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) length: Option<usize>,
+    // Path segments relative to the torrent's top level directory, the last
+    // segment being the file name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) path: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) md5sum: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct BencodeInfo {
-    pub(crate) name: String,
-    #[serde(rename = "piece length")]
-    pub(crate) piece_length: usize,
-    pub(crate) pieces: Bytes,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
+    #[serde(
+        default,
+        rename = "piece length",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) piece_length: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pieces: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub(crate) length: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) files: Option<Vec<BencodeFile>>,
 }
 
 impl BencodeInfo {
-    fn hash(&self) -> InfoHash {
-        let serialized = serde_bencode::to_bytes(&self).unwrap();
-        sha1::Sha1::from(serialized).digest().bytes()
-    }
-
-    fn split_piece_hashes(&self) -> PieceHashes {
+    fn split_piece_hashes(&self) -> Result<PieceHashes, MetainfoError> {
         let hash_len = 20;
-        if self.pieces.len() % hash_len != 0 {
-            panic!("Received malformed pieces of length {}", self.pieces.len());
+        let pieces = required(self.pieces.as_ref(), "pieces")?;
+        if !pieces.len().is_multiple_of(hash_len) {
+            return Err(MetainfoError::InvalidPieceHashes(pieces.len()));
         }
-        let piece_hashes: PieceHashes = self
-            .pieces
+        let piece_hashes: PieceHashes = pieces
             .chunks(hash_len)
             .map(|w| w.try_into().unwrap())
             .collect();
-        piece_hashes
+        Ok(piece_hashes)
+    }
+
+    // Single file torrents have a `length` and use `name` as the file name,
+    // multi-file torrents have a `files` list and use `name` as the
+    // directory the files are placed in.
+    fn file_table(&self, name: &str) -> Result<Vec<FileInfo>, MetainfoError> {
+        check_path_segment(name)?;
+        let mut offset = 0;
+        match (&self.files, self.length) {
+            (Some(files), _) => files
+                .iter()
+                .map(|file| {
+                    let length = required(file.length, "length")?;
+                    let segments = required(file.path.as_ref(), "path")?;
+                    if segments.is_empty() {
+                        return Err(MetainfoError::InvalidPath(String::new()));
+                    }
+                    let mut path = PathBuf::from(name);
+                    for segment in segments {
+                        check_path_segment(segment)?;
+                        path.push(segment);
+                    }
+                    let info = FileInfo {
+                        path,
+                        length,
+                        offset,
+                    };
+                    offset += length;
+                    Ok(info)
+                })
+                .collect(),
+            (None, Some(length)) => Ok(vec![FileInfo {
+                path: PathBuf::from(name),
+                length,
+                offset,
+            }]),
+            (None, None) => Err(MetainfoError::MissingKey(String::from("length"))),
+        }
     }
 }
 
+// Keys the spec requires are still decoded as optional, so that a missing
+// one can be reported by name.
+fn required<T>(value: Option<T>, key: &str) -> Result<T, MetainfoError> {
+    value.ok_or_else(|| MetainfoError::MissingKey(String::from(key)))
+}
+
+// File names come straight from whoever made the torrent, so anything that
+// could make us write outside of the download directory is rejected: empty
+// names, `.` and `..`, and names with separators that would be interpreted
+// as multiple components.
+fn check_path_segment(segment: &str) -> Result<(), MetainfoError> {
+    let is_unsafe = segment.is_empty()
+        || segment == "."
+        || segment == ".."
+        || segment.contains(['/', '\\', '\0'])
+        || Path::new(segment).has_root();
+    if is_unsafe {
+        return Err(MetainfoError::InvalidPath(String::from(segment)));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct BencodeTorrent {
-    pub(crate) info: BencodeInfo,
+    #[serde(default)]
+    pub(crate) info: Option<BencodeInfo>,
     #[serde(default)]
     pub(crate) announce: Option<String>,
+    // BEP 12, tiers of tracker urls.  Takes precedence over `announce`.
+    #[serde(default, rename = "announce-list")]
+    pub(crate) announce_list: Option<Vec<Vec<String>>>,
+    // The info dictionary exactly as it was encoded in the torrent file,
+    // filled in once decoding has succeeded.
+    #[serde(skip)]
+    pub(crate) raw_info: Bytes,
+}
+
+#[derive(Debug)]
+pub enum MetainfoError {
+    // The torrent file couldn't be read.
+    Io(io::Error),
+    // The data isn't valid bencode, or a value has the wrong type.
+    Bencode(String),
+    // A key required by the spec is absent.
+    MissingKey(String),
+    // `pieces` must be a concatenation of 20 byte SHA-1 hashes.
+    InvalidPieceHashes(usize),
+    // `piece length` must be positive.
+    InvalidPieceLength,
+    // The number of piece hashes doesn't cover the torrent's total length.
+    PieceCountMismatch { expected: usize, actual: usize },
+    // A file name that would escape the download directory.
+    InvalidPath(String),
+}
+
+impl fmt::Display for MetainfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use MetainfoError::*;
+        match self {
+            Io(e) => write!(f, "unable to read torrent file: {}", e),
+            Bencode(e) => write!(f, "invalid bencode: {}", e),
+            MissingKey(key) => write!(f, "missing required key `{}`", key),
+            InvalidPieceHashes(len) => write!(
+                f,
+                "pieces has length {}, which is not a multiple of 20",
+                len
+            ),
+            InvalidPieceLength => write!(f, "piece length must be greater than zero"),
+            PieceCountMismatch { expected, actual } => write!(
+                f,
+                "torrent length requires {} pieces but {} hashes were given",
+                expected, actual
+            ),
+            InvalidPath(path) => write!(f, "unsafe file path {:?}", path),
+        }
+    }
+}
+
+impl error::Error for MetainfoError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MetainfoError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MetainfoError {
+    fn from(e: io::Error) -> Self {
+        MetainfoError::Io(e)
+    }
+}
+
+impl From<serde_bencode::Error> for MetainfoError {
+    fn from(e: serde_bencode::Error) -> Self {
+        MetainfoError::Bencode(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    // Path of the file relative to the download directory.  For multi-file
+    // torrents this includes the torrent's name as the first component.
+    pub path: PathBuf,
+    pub length: usize,
+    // Offset of the file's first byte in the torrent as a whole, as if every
+    // file were concatenated together in order.
+    pub offset: usize,
+}
+
+// The part of a piece that falls into a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    // Where the slice starts within the file.
+    pub file_offset: usize,
+    // Where the slice starts within the piece.
+    pub piece_offset: usize,
+    pub length: usize,
 }
 
 #[derive(Debug)]
 pub struct Info {
-    name: String,
+    pub name: String,
     pub piece_length: usize,
-    pub pieces: Bytes,
+    // Total length of the torrent, the sum of every file's length.
     pub length: usize,
+    pub files: Vec<FileInfo>,
     pub info_hash: InfoHash,
+    // The bencoded info dictionary the info hash was computed from, kept
+    // around so it can be handed to peers that ask for the metadata.
+    pub raw: Bytes,
 }
 
 #[derive(Debug)]
 pub struct TorrentFile {
     pub info: Info,
-    pub announce: String,
+    // Tracker urls grouped into tiers, tried in order.  Torrents without an
+    // announce-list have a single tier holding their announce url.
+    pub announce_list: Vec<Vec<String>>,
     pub piece_hashes: PieceHashes,
     pub piece_count: usize,
 }
 
-impl From<BencodeTorrent> for TorrentFile {
-    fn from(bencode: BencodeTorrent) -> Self {
-        TorrentFile {
-            announce: bencode.announce.unwrap(),
-            piece_hashes: bencode.info.split_piece_hashes(),
-            info: Info {
-                name: bencode.info.name.clone(),
-                piece_length: bencode.info.piece_length,
-                pieces: bencode.info.pieces.clone(),
-                length: bencode.info.length.unwrap(),
-                info_hash: bencode.info.hash(),
-            },
-            piece_count: bencode.info.pieces.len() / 20,
-        }
+impl TryFrom<BencodeTorrent> for TorrentFile {
+    type Error = MetainfoError;
+
+    fn try_from(bencode: BencodeTorrent) -> Result<Self, Self::Error> {
+        let info = required(bencode.info, "info")?;
+        let announce_list = match (bencode.announce_list, bencode.announce) {
+            (Some(tiers), _) if tiers.iter().any(|tier| !tier.is_empty()) => {
+                tiers.into_iter().filter(|tier| !tier.is_empty()).collect()
+            }
+            (_, Some(announce)) => vec![vec![announce]],
+            _ => return Err(MetainfoError::MissingKey(String::from("announce"))),
+        };
+        TorrentFile::from_info(info, bencode.raw_info, announce_list)
     }
 }
 
 impl TorrentFile {
-    // Opens the torrent file, allocs a buffer for the metadata to go into,
-    // then uses serde to decode to a BencodeTorrent.  This is then converted
-    // to a TorrentFile. Intermediate representation is required because a sha1
-    // hash of the info dictionary object must be made for the tracker request,
-    // however serde can't do that as it deserializes so this is the workaround.
-    pub fn new(filename: &str) -> Self {
-        let mut file = fs::File::open(filename).expect("unable to read file");
-        let metadata = fs::metadata(&filename).expect("unable to read metadata");
-        let mut buffer = vec![0; metadata.len() as usize];
-        file.read_exact(&mut buffer).expect("buffer overflow");
-        let t = match de::from_bytes::<BencodeTorrent>(&buffer) {
-            Ok(t) => t,
-            Err(e) => panic!("Error: {:?}", e),
-        };
-        TorrentFile::from(t)
+    // Everything but the trackers comes from the info dictionary, `raw_info`
+    // being that dictionary as it was encoded.
+    fn from_info(
+        info: BencodeInfo,
+        raw_info: Bytes,
+        announce_list: Vec<Vec<String>>,
+    ) -> Result<Self, MetainfoError> {
+        let name = required(info.name.as_deref(), "name")?;
+        let piece_length = required(info.piece_length, "piece length")?;
+        if piece_length == 0 {
+            return Err(MetainfoError::InvalidPieceLength);
+        }
+        let piece_hashes = info.split_piece_hashes()?;
+        let files = info.file_table(name)?;
+        let length: usize = files.iter().map(|file| file.length).sum();
+        let expected = length.div_ceil(piece_length);
+        if piece_hashes.len() != expected {
+            return Err(MetainfoError::PieceCountMismatch {
+                expected,
+                actual: piece_hashes.len(),
+            });
+        }
+        Ok(TorrentFile {
+            announce_list,
+            piece_count: piece_hashes.len(),
+            piece_hashes,
+            info: Info {
+                name: String::from(name),
+                piece_length,
+                length,
+                files,
+                info_hash: sha1::Sha1::from(&raw_info).digest().bytes(),
+                raw: raw_info,
+            },
+        })
+    }
+
+    // Uses serde to decode the metainfo to a BencodeTorrent, which is then
+    // converted to a TorrentFile. Intermediate representation is required
+    // because a sha1 hash of the info dictionary object must be made for the
+    // tracker request, however serde can't do that as it deserializes.
+    // Re-encoding the decoded info would drop any key we don't model, so the
+    // hash is taken over the dictionary's original bytes instead.
+    pub fn from_bytes(buffer: &[u8]) -> Result<Self, MetainfoError> {
+        check_nesting(buffer)?;
+        let mut t = de::from_bytes::<BencodeTorrent>(buffer)?;
+        let span = bencode::dict_value_span(buffer, b"info")
+            .map_err(|e| MetainfoError::Bencode(e.to_string()))?
+            .ok_or_else(|| MetainfoError::MissingKey(String::from("info")))?;
+        t.raw_info = Bytes::copy_from_slice(&buffer[span]);
+        TorrentFile::try_from(t)
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, MetainfoError> {
+        let buffer = fs::read(path)?;
+        TorrentFile::from_bytes(&buffer)
+    }
+
+    // Builds a TorrentFile from a bare info dictionary, as fetched from peers
+    // when starting from a magnet link.  Magnet links needn't name any
+    // trackers, so unlike a torrent file `announce_list` may be empty.
+    pub fn from_info_bytes(
+        info: &[u8],
+        announce_list: Vec<Vec<String>>,
+    ) -> Result<Self, MetainfoError> {
+        let announce_list = announce_list
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        check_nesting(info)?;
+        TorrentFile::from_info(
+            de::from_bytes::<BencodeInfo>(info)?,
+            Bytes::copy_from_slice(info),
+            announce_list,
+        )
+    }
+
+    pub fn files(&self) -> &[FileInfo] {
+        &self.info.files
     }
 
     pub fn calculate_bounds_for_piece(&self, index: usize) -> (usize, usize) {
@@ -114,4 +351,346 @@ impl TorrentFile {
         let (start, finish) = self.calculate_bounds_for_piece(index);
         finish - start
     }
+
+    // Pieces are laid out over the concatenation of every file, so a piece
+    // may start in one file and finish in another (or several others if the
+    // files are small).  This returns the part of the piece that lands in
+    // each file it touches, in order.
+    pub fn file_slices_for_piece(&self, index: usize) -> Vec<FileSlice> {
+        let (start, end) = self.calculate_bounds_for_piece(index);
+        file_slices(self.files(), start, end)
+    }
+}
+
+#[cfg(test)]
+impl TorrentFile {
+    // A multi-file torrent named `test` holding `files`, named `f0`, `f1`
+    // and so on, with piece hashes that match their contents.
+    pub(crate) fn for_tests(piece_length: usize, files: &[&[u8]]) -> Self {
+        let data = files.concat();
+        let mut info = b"d5:filesl".to_vec();
+        for (i, file) in files.iter().enumerate() {
+            let name = format!("f{}", i);
+            info.extend(
+                format!("d6:lengthi{}e4:pathl{}:{}ee", file.len(), name.len(), name).bytes(),
+            );
+        }
+        let pieces: Vec<u8> = data
+            .chunks(piece_length)
+            .flat_map(|piece| sha1::Sha1::from(piece).digest().bytes())
+            .collect();
+        info.extend(
+            format!(
+                "e4:name4:test12:piece lengthi{}e6:pieces{}:",
+                piece_length,
+                pieces.len()
+            )
+            .bytes(),
+        );
+        info.extend(pieces);
+        info.push(b'e');
+        let announce_list = vec![vec![String::from("http://tracker/announce")]];
+        TorrentFile::from_info_bytes(&info, announce_list).unwrap()
+    }
+}
+
+// Helpers shared by the tests of several modules.
+#[cfg(test)]
+pub(crate) mod test_helpers {
+    use crate::client::scheduler::WorkerId;
+    use crate::client::types::Bitfield;
+
+    pub(crate) fn bitfield(bits: &[bool]) -> Bitfield {
+        bits.iter().copied().collect()
+    }
+
+    // Workers are only known by task id, these come from tasks that have
+    // already finished.
+    pub(crate) async fn workers(count: usize) -> Vec<WorkerId> {
+        let mut workers = Vec::new();
+        for _ in 0..count {
+            let handle = tokio::spawn(async {});
+            workers.push(handle.id());
+            handle.await.unwrap();
+        }
+        workers
+    }
+}
+
+// serde_bencode recurses into every value, including keys we don't model,
+// with no limit on how deep.  Walking the encoding first turns absurdly
+// nested input into an error rather than a stack overflow.
+fn check_nesting(buffer: &[u8]) -> Result<(), MetainfoError> {
+    bencode::value_len(buffer)
+        .map(|_| ())
+        .map_err(|e| MetainfoError::Bencode(e.to_string()))
+}
+
+// The parts of the torrent's byte range `start..end` that land in each file,
+// with `piece_offset` counted from `start`.
+pub(crate) fn file_slices(files: &[FileInfo], start: usize, end: usize) -> Vec<FileSlice> {
+    files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| file.offset < end && file.offset + file.length > start)
+        .map(|(file_index, file)| {
+            let slice_start = std::cmp::max(start, file.offset);
+            let slice_end = std::cmp::min(end, file.offset + file.length);
+            FileSlice {
+                file_index,
+                file_offset: slice_start - file.offset,
+                piece_offset: slice_start - start,
+                length: slice_end - slice_start,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A piece hash, its value doesn't matter here.
+    const HASH: &str = "aaaaaaaaaaaaaaaaaaaa";
+
+    fn torrent(info: &str) -> Vec<u8> {
+        format!("d8:announce9:udp://x:14:info{}e", info).into_bytes()
+    }
+
+    fn single_file(length: usize, piece_length: usize, pieces: usize) -> Vec<u8> {
+        torrent(&format!(
+            "d6:lengthi{}e4:name3:foo12:piece lengthi{}e6:pieces{}:{}e",
+            length,
+            piece_length,
+            pieces * HASH.len(),
+            HASH.repeat(pieces)
+        ))
+    }
+
+    fn multi_file(path: &str) -> Vec<u8> {
+        torrent(&format!(
+            "d5:filesld6:lengthi10e4:path{}ee4:name3:foo12:piece lengthi16e6:pieces20:{}e",
+            path, HASH
+        ))
+    }
+
+    #[test]
+    fn single_file_torrent() {
+        let torrent = TorrentFile::from_bytes(&single_file(40, 16, 3)).unwrap();
+        assert_eq!(torrent.announce_list, vec![vec![String::from("udp://x:1")]]);
+        assert_eq!(torrent.piece_count, 3);
+        assert_eq!(torrent.info.length, 40);
+        assert_eq!(torrent.info.files[0].path, PathBuf::from("foo"));
+        assert_eq!(torrent.calculate_piece_size(2), 8);
+    }
+
+    #[test]
+    fn info_hash_is_taken_over_original_bytes() {
+        // an unknown key we don't model still counts towards the hash
+        let info = format!(
+            "d6:lengthi10e4:name3:foo12:piece lengthi16e6:pieces20:{}7:unknowni1ee",
+            HASH
+        );
+        let torrent = TorrentFile::from_bytes(&torrent(&info)).unwrap();
+        let expected = sha1::Sha1::from(info.as_bytes()).digest().bytes();
+        assert_eq!(torrent.info.info_hash, expected);
+        assert_eq!(&torrent.info.raw[..], info.as_bytes());
+    }
+
+    #[test]
+    fn announce_list_takes_precedence() {
+        let info = format!(
+            "d6:lengthi10e4:name3:foo12:piece lengthi16e6:pieces20:{}e",
+            HASH
+        );
+        let buf = format!(
+            "d8:announce9:udp://x:113:announce-listllel3:abc3:defee4:info{}e",
+            info
+        );
+        let torrent = TorrentFile::from_bytes(buf.as_bytes()).unwrap();
+        assert_eq!(
+            torrent.announce_list,
+            vec![vec![String::from("abc"), String::from("def")]]
+        );
+    }
+
+    #[test]
+    fn info_bytes_without_trackers() {
+        let info = format!(
+            "d6:lengthi10e4:name3:foo12:piece lengthi16e6:pieces20:{}e",
+            HASH
+        );
+        let torrent = TorrentFile::from_info_bytes(info.as_bytes(), Vec::new()).unwrap();
+        assert!(torrent.announce_list.is_empty());
+        assert_eq!(torrent.piece_count, 1);
+        let torrent = TorrentFile::from_info_bytes(info.as_bytes(), vec![Vec::new()]).unwrap();
+        assert!(torrent.announce_list.is_empty());
+    }
+
+    #[test]
+    fn missing_keys() {
+        let info = format!(
+            "d6:lengthi10e4:name3:foo12:piece lengthi16e6:pieces20:{}e",
+            HASH
+        );
+        let no_announce = format!("d4:info{}e", info);
+        assert!(matches!(
+            TorrentFile::from_bytes(no_announce.as_bytes()),
+            Err(MetainfoError::MissingKey(key)) if key == "announce"
+        ));
+        assert!(matches!(
+            TorrentFile::from_bytes(b"d8:announce3:urle"),
+            Err(MetainfoError::MissingKey(key)) if key == "info"
+        ));
+        let no_length = torrent(&format!(
+            "d4:name3:foo12:piece lengthi16e6:pieces20:{}e",
+            HASH
+        ));
+        assert!(matches!(
+            TorrentFile::from_bytes(&no_length),
+            Err(MetainfoError::MissingKey(key)) if key == "length"
+        ));
+        let cases = [
+            (
+                "name",
+                format!("d6:lengthi10e12:piece lengthi16e6:pieces20:{}e", HASH),
+            ),
+            (
+                "piece length",
+                format!("d6:lengthi10e4:name3:foo6:pieces20:{}e", HASH),
+            ),
+            (
+                "pieces",
+                String::from("d6:lengthi10e4:name3:foo12:piece lengthi16ee"),
+            ),
+            (
+                "path",
+                format!(
+                    "d5:filesld6:lengthi10eee4:name3:foo12:piece lengthi16e6:pieces20:{}e",
+                    HASH
+                ),
+            ),
+            (
+                "length",
+                format!(
+                    "d5:filesld4:pathl1:aeee4:name3:foo12:piece lengthi16e6:pieces20:{}e",
+                    HASH
+                ),
+            ),
+        ];
+        for (missing, info) in cases {
+            assert!(
+                matches!(
+                    TorrentFile::from_bytes(&torrent(&info)),
+                    Err(MetainfoError::MissingKey(key)) if key == missing
+                ),
+                "no error for missing {}",
+                missing
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_bencode() {
+        assert!(matches!(
+            TorrentFile::from_bytes(b"not bencode"),
+            Err(MetainfoError::Bencode(_))
+        ));
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let depth = 200_000;
+        let mut info = format!(
+            "d6:lengthi10e4:name3:foo12:piece lengthi16e6:pieces20:{}7:unknown",
+            HASH
+        )
+        .into_bytes();
+        info.extend(vec![b'l'; depth]);
+        info.extend(vec![b'e'; depth]);
+        info.push(b'e');
+        assert!(matches!(
+            TorrentFile::from_info_bytes(&info, Vec::new()),
+            Err(MetainfoError::Bencode(_))
+        ));
+        let mut buf = b"d8:announce3:url4:info".to_vec();
+        buf.extend(info);
+        buf.push(b'e');
+        assert!(matches!(
+            TorrentFile::from_bytes(&buf),
+            Err(MetainfoError::Bencode(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_pieces() {
+        let buf = torrent("d6:lengthi10e4:name3:foo12:piece lengthi16e6:pieces3:abce");
+        assert!(matches!(
+            TorrentFile::from_bytes(&buf),
+            Err(MetainfoError::InvalidPieceHashes(3))
+        ));
+        assert!(matches!(
+            TorrentFile::from_bytes(&single_file(10, 0, 1)),
+            Err(MetainfoError::InvalidPieceLength)
+        ));
+        assert!(matches!(
+            TorrentFile::from_bytes(&single_file(40, 16, 2)),
+            Err(MetainfoError::PieceCountMismatch {
+                expected: 3,
+                actual: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        for path in ["l2:..e", "l1:.e", "le", "l0:e", "l3:a/be", "l4:/etce"] {
+            assert!(
+                matches!(
+                    TorrentFile::from_bytes(&multi_file(path)),
+                    Err(MetainfoError::InvalidPath(_))
+                ),
+                "{} was accepted",
+                path
+            );
+        }
+        let torrent = TorrentFile::from_bytes(&multi_file("l3:sub3:bare")).unwrap();
+        assert_eq!(torrent.info.files[0].path, PathBuf::from("foo/sub/bar"));
+    }
+
+    #[test]
+    fn slices_span_files() {
+        let info = format!(
+            "d5:filesld6:lengthi10e4:pathl1:aeed6:lengthi20e4:pathl1:beee\
+             4:name3:foo12:piece lengthi16e6:pieces40:{}e",
+            HASH.repeat(2)
+        );
+        let torrent = TorrentFile::from_bytes(&torrent(&info)).unwrap();
+        assert_eq!(
+            torrent.file_slices_for_piece(0),
+            vec![
+                FileSlice {
+                    file_index: 0,
+                    file_offset: 0,
+                    piece_offset: 0,
+                    length: 10
+                },
+                FileSlice {
+                    file_index: 1,
+                    file_offset: 0,
+                    piece_offset: 10,
+                    length: 6
+                },
+            ]
+        );
+        assert_eq!(
+            torrent.file_slices_for_piece(1),
+            vec![FileSlice {
+                file_index: 1,
+                file_offset: 6,
+                piece_offset: 0,
+                length: 14
+            }]
+        );
+    }
 }
@@ -1,43 +1,185 @@
 // use rand::RngCore;
-use super::torrent::TorrentFile;
-use super::types::PeerId;
+use super::peer::{Peer, COMPACT_PEER6_LEN, COMPACT_PEER_LEN};
+use super::stats::TransferStats;
+use super::types::{InfoHash, PeerId, Peers};
+use super::udp_tracker::UdpTracker;
+use super::LISTEN_PORT;
 
 use bytes::Bytes;
+use futures::future::join_all;
+use rand::seq::SliceRandom;
+use reqwest::StatusCode;
+use serde_bencode::de;
 use serde_derive::{Deserialize, Serialize};
-use std::fmt;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+use std::{error, fmt, io};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug)]
+pub enum TrackerError {
+    // The tracker refused the announce, with the reason it gave.
+    Failure(String),
+    // The request never made it to the tracker, or the response never made
+    // it back.
+    Http(reqwest::Error),
+    Io(io::Error),
+    // The tracker answered with an error status and no failure reason.
+    Status(u16),
+    // No response arrived in time, even after retransmitting.
+    Timeout,
+    // The announce url can't be used.
+    InvalidUrl(String),
+    // The tracker answered with something we couldn't make sense of.
+    InvalidResponse(String),
+    // The announce url doesn't follow the `/announce` convention, so there's
+    // no scrape url to derive from it.
+    ScrapeUnsupported(String),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TrackerError::*;
+        match self {
+            Failure(reason) => write!(f, "tracker failure: {}", reason),
+            Http(e) => write!(f, "http error: {}", e),
+            Io(e) => write!(f, "io error: {}", e),
+            Status(status) => write!(f, "tracker responded with status {}", status),
+            Timeout => write!(f, "tracker did not respond"),
+            InvalidUrl(url) => write!(f, "invalid tracker url {}", url),
+            InvalidResponse(e) => write!(f, "invalid tracker response: {}", e),
+            ScrapeUnsupported(url) => write!(f, "tracker {} does not support scrape", url),
+        }
+    }
+}
+
+impl error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TrackerError::Http(e) => Some(e),
+            TrackerError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            TrackerError::Timeout
+        } else {
+            TrackerError::Http(e)
+        }
+    }
+}
+
+impl From<io::Error> for TrackerError {
+    fn from(e: io::Error) -> Self {
+        TrackerError::Io(e)
+    }
+}
+
+// Lifecycle events reported to trackers.  Regular re-announces have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    // The first announce of a download.
+    Started,
+    // Sent once when the download finishes, but not if it was already
+    // complete when we started.
+    Completed,
+    // We are shutting down.
+    Stopped,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Started => write!(f, "started"),
+            Event::Completed => write!(f, "completed"),
+            Event::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+// How many peers to ask for when announcing, trackers default to 50 too.
+const NUM_WANT: u32 = 50;
+// How long an HTTP tracker gets to answer an announce.  UDP trackers give up
+// on their own once their retransmit schedule runs out.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
-    announce: String,
-    info_hash: String,
-    peer_id: String,
-    port: i32,
-    uploaded: u8,
-    downloaded: u8,
-    compact: u8,
-    left: usize,
+    pub(crate) info_hash: InfoHash,
+    pub(crate) peer_id: PeerId,
+    pub(crate) port: i32,
+    pub(crate) uploaded: u64,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+    pub(crate) compact: u8,
+    pub(crate) event: Option<Event>,
+    pub(crate) numwant: u32,
+    // Random value that stays the same for the whole session, lets trackers
+    // recognise us if our IP address changes.
+    pub(crate) key: u32,
+    // Echoed back to trackers that handed one out, set by `Tracker`.
+    pub(crate) trackerid: Option<String>,
+    // BEP 7, our IPv6 address.  Lets a tracker we reach over IPv4 hand us
+    // out to IPv6 peers too.
+    pub(crate) ipv6: Option<Ipv6Addr>,
 }
 
 impl TrackerRequest {
-    pub fn new_from_torrent(torrent: &TorrentFile, peer_id: PeerId) -> Self {
+    pub fn new(info_hash: InfoHash, peer_id: PeerId, left: u64) -> Self {
         Self {
-            announce: torrent.announce.clone(),
-            info_hash: torrent
-                .info
-                .info_hash
-                .iter()
-                .map(|v| format!("%{:02X}", v))
-                .collect::<String>(),
-            peer_id: peer_id
-                .iter()
-                .map(|v| format!("%{:02X}", v))
-                .collect::<String>(),
-            port: 6881,
+            info_hash,
+            peer_id,
+            port: LISTEN_PORT as i32,
             uploaded: 0,
             downloaded: 0,
+            left,
             compact: 1,
-            left: torrent.info.length,
+            event: None,
+            numwant: NUM_WANT,
+            key: 0,
+            trackerid: None,
+            ipv6: None,
+        }
+    }
+
+    pub fn new_from_stats(
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        key: u32,
+        stats: &TransferStats,
+    ) -> Self {
+        Self {
+            uploaded: stats.uploaded(),
+            downloaded: stats.downloaded(),
+            key,
+            ..TrackerRequest::new(info_hash, peer_id, stats.left())
+        }
+    }
+
+    // The port we're accepting peers on, if it isn't the default.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port as i32;
+        self
+    }
+
+    pub fn with_ipv6(mut self, ipv6: Option<Ipv6Addr>) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+
+    pub fn with_event(mut self, event: Event) -> Self {
+        self.event = Some(event);
+        if event == Event::Stopped {
+            // we're going away, no point in being sent peers
+            self.numwant = 0;
         }
+        self
     }
 }
 
@@ -46,14 +188,642 @@ impl fmt::Display for TrackerRequest {
     // when urlencoding the info_hash and peer_id. It's doing something funky
     // and both are being converted incorrectly.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{announce}?compact={compact}&downloaded={downloaded}&info_hash={info_hash}&peer_id={peer_id}&port={port}&uploaded={uploaded}",
-    announce = self.announce,
-compact = self.compact, downloaded=self.downloaded, info_hash = self.info_hash, peer_id=self.peer_id, port=self.port, uploaded = self.uploaded)
+        let info_hash = self
+            .info_hash
+            .iter()
+            .map(|v| format!("%{:02X}", v))
+            .collect::<String>();
+        let peer_id = self
+            .peer_id
+            .iter()
+            .map(|v| format!("%{:02X}", v))
+            .collect::<String>();
+        write!(f, "info_hash={info_hash}&peer_id={peer_id}&port={port}&uploaded={uploaded}&downloaded={downloaded}&left={left}&compact={compact}&numwant={numwant}&key={key:08x}",
+info_hash = info_hash, peer_id=peer_id, port=self.port, uploaded = self.uploaded, downloaded=self.downloaded, left=self.left, compact = self.compact, numwant=self.numwant, key=self.key)?;
+        if let Some(event) = self.event {
+            write!(f, "&event={}", event)?;
+        }
+        if let Some(trackerid) = &self.trackerid {
+            let param =
+                serde_urlencoded::to_string([("trackerid", trackerid)]).map_err(|_| fmt::Error)?;
+            write!(f, "&{}", param)?;
+        }
+        if let Some(ipv6) = self.ipv6 {
+            write!(f, "&ipv6={}", ipv6)?;
+        }
+        Ok(())
+    }
+}
+
+// Finds the address we'd use to reach the IPv6 internet, if we have one.
+// Connecting a UDP socket only picks a route, nothing is actually sent.
+// Anything other than a global unicast address (2000::/3) would be useless
+// to peers, so those are ignored.
+pub(crate) fn local_ipv6_addr() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind("[::]:0").ok()?;
+    socket.connect("[2001:4860:4860::8888]:80").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(addr) if addr.segments()[0] & 0xe000 == 0x2000 => Some(addr),
+        _ => None,
     }
 }
 
+// Trackers answer with a compact string of 6 byte entries unless we ask for
+// `compact=0` or they don't support it, in which case every peer gets a
+// dictionary of its own.  IPv6 peers come separately, see `peers6`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PeerList {
+    Compact(Bytes),
+    Dictionaries(Vec<PeerDictionary>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PeerDictionary {
+    #[serde(default, rename = "peer id")]
+    pub peer_id: Option<Bytes>,
+    // IPv4 or IPv6 address, or a DNS name
+    pub ip: String,
+    pub port: u16,
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        PeerList::Compact(Bytes::new())
+    }
+}
+
+impl PeerList {
+    pub fn to_peers(&self) -> Peers {
+        match self {
+            PeerList::Compact(blob) => compact_peers(blob, COMPACT_PEER_LEN),
+            // Peers given by DNS name are rare enough that they're skipped
+            // rather than resolved.
+            PeerList::Dictionaries(peers) => peers
+                .iter()
+                .filter_map(|peer| peer.ip.parse::<IpAddr>().ok().map(|ip| (ip, peer.port)))
+                .map(|(ip, port)| Peer::from(SocketAddr::new(ip, port)))
+                .collect(),
+        }
+    }
+}
+
+// A trailing partial entry means the tracker sent us garbage, the complete
+// entries before it are still used.
+fn compact_peers(blob: &[u8], entry_len: usize) -> Peers {
+    blob.chunks_exact(entry_len)
+        .filter_map(Peer::from_compact)
+        .collect()
+}
+
 #[derive(Debug, Deserialize)]
 pub struct TrackerResponse {
+    // Present instead of everything else when the announce was refused.
+    #[serde(default, rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    // The announce went through, but the tracker has something to say.
+    #[serde(default, rename = "warning message")]
+    pub warning_message: Option<String>,
+    #[serde(default)]
     pub interval: u32,
-    pub peers: Bytes,
+    // Trackers may ask that clients never re-announce more often than this,
+    // even when the regular interval is shorter.
+    #[serde(default, rename = "min interval")]
+    pub min_interval: Option<u32>,
+    // Should be sent back on our next announce to the same tracker.
+    #[serde(default, rename = "tracker id")]
+    pub tracker_id: Option<String>,
+    // seeders
+    #[serde(default)]
+    pub complete: Option<u32>,
+    // leechers
+    #[serde(default)]
+    pub incomplete: Option<u32>,
+    #[serde(default)]
+    pub peers: PeerList,
+    // BEP 7, compact IPv6 peers in 18 byte entries.
+    #[serde(default)]
+    pub peers6: Bytes,
+}
+
+impl TrackerResponse {
+    // Seconds to wait before the next regular announce.  Trackers may set a
+    // `min interval` longer than their regular interval, we never announce
+    // more often than either allows.
+    pub fn next_interval(&self) -> u32 {
+        std::cmp::max(self.interval, self.min_interval.unwrap_or(0))
+    }
+
+    // Every peer the tracker handed out, of either address family.
+    pub fn to_peers(&self) -> Peers {
+        let mut peers = self.peers.to_peers();
+        peers.extend(compact_peers(&self.peers6, COMPACT_PEER6_LEN));
+        peers
+    }
+}
+
+// How many peers a tracker knows of for a torrent, as returned by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct ScrapeStats {
+    // seeders
+    pub complete: u32,
+    // number of times the torrent has been fully downloaded
+    pub downloaded: u32,
+    // leechers
+    pub incomplete: u32,
+}
+
+// How many info hashes to put in a single HTTP scrape, to keep the url at a
+// length every tracker will accept.
+const MAX_HTTP_SCRAPE_HASHES: usize = 64;
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(default, rename = "failure reason")]
+    failure_reason: Option<String>,
+    // Keyed by the raw 20 byte info hash.
+    #[serde(default)]
+    files: HashMap<Bytes, ScrapeStats>,
+}
+
+// A tracker taken from the torrent's metainfo, with the protocol picked from
+// the scheme of its announce url.
+#[derive(Debug)]
+pub enum Tracker {
+    Http(HttpTracker),
+    Udp(UdpTracker),
+}
+
+#[derive(Debug)]
+pub struct HttpTracker {
+    url: String,
+    tracker_id: Option<String>,
+    client: reqwest::Client,
+}
+
+// A tracker that stalls is given up on rather than waited for forever.
+fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(ANNOUNCE_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+}
+
+impl Tracker {
+    pub fn new(url: &str) -> Self {
+        Tracker::with_client(url, http_client())
+    }
+
+    // Lets HTTP trackers share a client, and with it a connection pool.
+    fn with_client(url: &str, client: reqwest::Client) -> Self {
+        if url.starts_with("udp://") {
+            Tracker::Udp(UdpTracker::new(url))
+        } else {
+            Tracker::Http(HttpTracker {
+                url: String::from(url),
+                tracker_id: None,
+                client,
+            })
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            Tracker::Http(tracker) => &tracker.url,
+            Tracker::Udp(tracker) => tracker.url(),
+        }
+    }
+
+    pub async fn announce(
+        &mut self,
+        req: &TrackerRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        match self {
+            Tracker::Http(tracker) => tracker.announce(req).await,
+            Tracker::Udp(tracker) => tracker.announce(req).await,
+        }
+    }
+
+    // Asks the tracker about several torrents at once without announcing.
+    // Stats are returned in the same order as `info_hashes`, torrents the
+    // tracker doesn't know about have all zero stats.
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[InfoHash],
+    ) -> Result<Vec<ScrapeStats>, TrackerError> {
+        match self {
+            Tracker::Http(tracker) => tracker.scrape(info_hashes).await,
+            Tracker::Udp(tracker) => tracker.scrape(info_hashes).await,
+        }
+    }
+}
+
+impl HttpTracker {
+    async fn announce(&mut self, req: &TrackerRequest) -> Result<TrackerResponse, TrackerError> {
+        // private trackers often put a passkey in the announce url
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let req = TrackerRequest {
+            trackerid: self.tracker_id.clone(),
+            ..req.clone()
+        };
+        let url = format!("{}{}{}", self.url, separator, req);
+        let res = self.client.get(&url).send().await?;
+        let status = res.status();
+        let body = res.bytes().await?;
+        let res = announce_response(status, &body)?;
+        if res.tracker_id.is_some() {
+            self.tracker_id = res.tracker_id.clone();
+        }
+        Ok(res)
+    }
+
+    async fn scrape(&self, info_hashes: &[InfoHash]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let scrape_url = self.scrape_url()?;
+        let separator = if scrape_url.contains('?') { '&' } else { '?' };
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_HTTP_SCRAPE_HASHES) {
+            let query = chunk
+                .iter()
+                .map(|info_hash| {
+                    let encoded = info_hash
+                        .iter()
+                        .map(|v| format!("%{:02X}", v))
+                        .collect::<String>();
+                    format!("info_hash={}", encoded)
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            let url = format!("{}{}{}", scrape_url, separator, query);
+            let res = self.client.get(&url).send().await?;
+            let status = res.status();
+            let body = res.bytes().await?;
+            stats.extend(scrape_response(status, &body, chunk)?);
+        }
+        Ok(stats)
+    }
+
+    // By convention the scrape url is the announce url with the `announce`
+    // at the start of its last path segment replaced by `scrape`, e.g.
+    // http://example.com/x/announce.php?passkey=abc becomes
+    // http://example.com/x/scrape.php?passkey=abc.  Trackers whose announce
+    // url doesn't look like that don't support scraping.
+    fn scrape_url(&self) -> Result<String, TrackerError> {
+        let (path, query) = match self.url.find('?') {
+            Some(i) => self.url.split_at(i),
+            None => (self.url.as_str(), ""),
+        };
+        let unsupported = || TrackerError::ScrapeUnsupported(self.url.clone());
+        let slash = path.rfind('/').ok_or_else(unsupported)?;
+        let (base, segment) = path.split_at(slash + 1);
+        let rest = segment.strip_prefix("announce").ok_or_else(unsupported)?;
+        Ok(format!("{}scrape{}{}", base, rest, query))
+    }
+}
+
+// Turns a tracker's answer into a response or the error it amounts to.  Some
+// trackers send their failure reason with an error status, so look for one
+// before giving up on the status.
+fn announce_response(status: StatusCode, body: &[u8]) -> Result<TrackerResponse, TrackerError> {
+    let res = match de::from_bytes::<TrackerResponse>(body) {
+        Ok(res) => res,
+        Err(_) if !status.is_success() => return Err(TrackerError::Status(status.as_u16())),
+        Err(e) => return Err(TrackerError::InvalidResponse(e.to_string())),
+    };
+    if let Some(reason) = res.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+    if !status.is_success() {
+        return Err(TrackerError::Status(status.as_u16()));
+    }
+    Ok(res)
+}
+
+// The stats for each of `info_hashes`, in the same order, from a tracker's
+// answer to a scrape.  Torrents the tracker left out have no peers.
+fn scrape_response(
+    status: StatusCode,
+    body: &[u8],
+    info_hashes: &[InfoHash],
+) -> Result<Vec<ScrapeStats>, TrackerError> {
+    let res = match de::from_bytes::<ScrapeResponse>(body) {
+        Ok(res) => res,
+        Err(_) if !status.is_success() => return Err(TrackerError::Status(status.as_u16())),
+        Err(e) => return Err(TrackerError::InvalidResponse(e.to_string())),
+    };
+    if let Some(reason) = res.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+    if !status.is_success() {
+        return Err(TrackerError::Status(status.as_u16()));
+    }
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| res.files.get(&info_hash[..]).copied().unwrap_or_default())
+        .collect())
+}
+
+// Every tracker for a torrent, grouped into tiers (BEP 12).
+#[derive(Debug)]
+pub struct AnnounceList {
+    tiers: Vec<Vec<Tracker>>,
+}
+
+impl AnnounceList {
+    // Trackers within a tier are shuffled once up front, as the spec asks,
+    // so load is spread between them.
+    pub fn new(tiers: &[Vec<String>]) -> Self {
+        let mut rng = rand::thread_rng();
+        let client = http_client();
+        let tiers = tiers
+            .iter()
+            .filter(|urls| !urls.is_empty())
+            .map(|urls| {
+                let mut tier: Vec<Tracker> = urls
+                    .iter()
+                    .map(|url| Tracker::with_client(url, client.clone()))
+                    .collect();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+        Self { tiers }
+    }
+
+    // True for torrents from magnet links that didn't name a tracker.
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    // Announces to every tier at once, trying each tier's trackers in order
+    // until one of them answers.  Returns what each tier had to say, its
+    // first answer or else the error from the last tracker tried.
+    pub async fn announce(
+        &mut self,
+        req: &TrackerRequest,
+    ) -> Vec<Result<TrackerResponse, TrackerError>> {
+        let tiers = self.tiers.iter_mut().map(|tier| announce_tier(tier, req));
+        join_all(tiers).await
+    }
+}
+
+// The tracker that answered is moved to the front of its tier so it is tried
+// first next time.  There is no timeout here: the HTTP client is built with
+// ANNOUNCE_TIMEOUT and a UDP tracker stops after its last retransmit, so a
+// dead tracker only holds up the rest of the tier for as long as its protocol
+// allows.
+async fn announce_tier(
+    tier: &mut Vec<Tracker>,
+    req: &TrackerRequest,
+) -> Result<TrackerResponse, TrackerError> {
+    let mut error = TrackerError::Timeout;
+    for i in 0..tier.len() {
+        match tier[i].announce(req).await {
+            Ok(res) => {
+                let tracker = tier.remove(i);
+                tier.insert(0, tracker);
+                return Ok(res);
+            }
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::udp_tracker::tests::StandIn;
+
+    fn socket_addrs(peers: &Peers) -> Vec<String> {
+        peers.iter().map(|peer| peer.to_string()).collect()
+    }
+
+    #[test]
+    fn compact_peers_of_both_families() {
+        let mut body = b"d8:intervali900e5:peers12:".to_vec();
+        body.extend([10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+        body.extend(b"6:peers618:");
+        body.extend([0xfe, 0x80]);
+        body.extend([0; 13]);
+        body.extend([1, 0x1a, 0xe1]);
+        body.push(b'e');
+        let res: TrackerResponse = de::from_bytes(&body).unwrap();
+        assert_eq!(
+            socket_addrs(&res.to_peers()),
+            ["10.0.0.1:6881", "10.0.0.2:6882", "[fe80::1]:6881"]
+        );
+    }
+
+    #[test]
+    fn partial_entries_are_dropped() {
+        let mut body = b"d5:peers8:".to_vec();
+        body.extend([10, 0, 0, 1, 0x1a, 0xe1, 10, 0]);
+        body.extend(b"6:peers64:");
+        body.extend([0xfe, 0x80, 0, 0]);
+        body.push(b'e');
+        let res: TrackerResponse = de::from_bytes(&body).unwrap();
+        assert_eq!(socket_addrs(&res.to_peers()), ["10.0.0.1:6881"]);
+    }
+
+    #[test]
+    fn dictionary_peers() {
+        let body = b"d5:peersld2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa\
+                     4:porti6881eed2:ip7:fe80::14:porti6882eed2:ip11:example.com\
+                     4:porti6883eeee";
+        let res: TrackerResponse = de::from_bytes(body).unwrap();
+        // names aren't resolved
+        assert_eq!(
+            socket_addrs(&res.to_peers()),
+            ["10.0.0.1:6881", "[fe80::1]:6882"]
+        );
+    }
+
+    #[test]
+    fn failure_reason_is_an_error() {
+        let body = b"d14:failure reason17:torrent not founde";
+        for status in [StatusCode::OK, StatusCode::NOT_FOUND] {
+            assert!(matches!(
+                announce_response(status, body),
+                Err(TrackerError::Failure(reason)) if reason == "torrent not found"
+            ));
+        }
+        assert!(matches!(
+            announce_response(StatusCode::BAD_GATEWAY, b"<html>"),
+            Err(TrackerError::Status(502))
+        ));
+        assert!(matches!(
+            announce_response(StatusCode::OK, b"<html>"),
+            Err(TrackerError::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn warning_comes_with_peers() {
+        let mut body = b"d8:intervali900e5:peers6:".to_vec();
+        body.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+        body.extend(b"15:warning message16:slow down pleasee");
+        let res = announce_response(StatusCode::OK, &body).unwrap();
+        assert_eq!(res.warning_message.as_deref(), Some("slow down please"));
+        assert_eq!(socket_addrs(&res.to_peers()), ["10.0.0.1:6881"]);
+    }
+
+    #[test]
+    fn scrape_stats_for_our_torrent() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend([1; 20]);
+        body.extend(b"d8:completei5e10:downloadedi50e10:incompletei10ee20:");
+        body.extend([2; 20]);
+        body.extend(b"d8:completei1eeee");
+        let stats = scrape_response(StatusCode::OK, &body, &[[1; 20], [3; 20]]).unwrap();
+        assert_eq!(
+            stats,
+            [
+                ScrapeStats {
+                    complete: 5,
+                    downloaded: 50,
+                    incomplete: 10,
+                },
+                // not one the tracker knows
+                ScrapeStats::default(),
+            ]
+        );
+        assert!(matches!(
+            scrape_response(StatusCode::OK, b"d14:failure reason4:nopee", &[[1; 20]]),
+            Err(TrackerError::Failure(_))
+        ));
+    }
+
+    fn scrape_url(announce: &str) -> Result<String, TrackerError> {
+        match Tracker::new(announce) {
+            Tracker::Http(tracker) => tracker.scrape_url(),
+            Tracker::Udp(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn scrape_url_replaces_announce() {
+        let cases = [
+            ("http://example.com/announce", "http://example.com/scrape"),
+            (
+                "http://example.com/x/announce",
+                "http://example.com/x/scrape",
+            ),
+            (
+                "http://example.com/announce.php?passkey=abc",
+                "http://example.com/scrape.php?passkey=abc",
+            ),
+            (
+                "http://example.com/announce?next=/announce",
+                "http://example.com/scrape?next=/announce",
+            ),
+        ];
+        for (announce, scrape) in cases {
+            assert_eq!(scrape_url(announce).unwrap(), scrape);
+        }
+    }
+
+    #[test]
+    fn scrape_unsupported() {
+        for announce in [
+            "http://example.com/a",
+            "http://example.com/x/announce/y",
+            "http://example.com/notannounce",
+        ] {
+            assert!(
+                matches!(
+                    scrape_url(announce),
+                    Err(TrackerError::ScrapeUnsupported(url)) if url == announce
+                ),
+                "{} has a scrape url",
+                announce
+            );
+        }
+    }
+
+    // A UDP tracker that never answers, so it times out after all of its
+    // retransmits, and the socket it sends to.
+    fn silent_tracker() -> (UdpSocket, UdpTracker) {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        silent.set_nonblocking(true).unwrap();
+        let url = format!("udp://{}/announce", silent.local_addr().unwrap());
+        let tracker = UdpTracker::new(&url).with_retransmit_timeout(Duration::from_millis(10));
+        (silent, tracker)
+    }
+
+    // How many connect requests have reached a silent tracker.
+    fn connects(silent: &UdpSocket) -> usize {
+        let mut buf = [0; 64];
+        let mut connects = 0;
+        while silent.recv_from(&mut buf).is_ok() {
+            connects += 1;
+        }
+        connects
+    }
+
+    #[tokio::test]
+    async fn tier_moves_on_once_udp_tracker_gives_up() {
+        let (silent, dead) = silent_tracker();
+        let url = dead.url().to_string();
+        let (stand_in, live) = StandIn::bind().await;
+        let mut tier = vec![Tracker::Udp(dead), Tracker::Udp(live)];
+
+        let req = TrackerRequest::new([1; 20], [2; 20], 1000);
+        let server = async {
+            stand_in.accept_connect().await;
+            stand_in.accept_announce(900).await;
+        };
+        let (response, _) = tokio::join!(announce_tier(&mut tier, &req), server);
+        assert_eq!(response.unwrap().interval, 900);
+
+        assert_eq!(connects(&silent), 3);
+        assert_ne!(tier[0].url(), url);
+        assert_eq!(tier[1].url(), url);
+    }
+
+    #[tokio::test]
+    async fn tracker_that_answered_is_tried_first() {
+        let (silent, dead) = silent_tracker();
+        let url = dead.url().to_string();
+        let (first, live) = StandIn::bind().await;
+        let (second, other) = StandIn::bind().await;
+        let mut trackers = AnnounceList {
+            tiers: vec![
+                vec![Tracker::Udp(dead), Tracker::Udp(live)],
+                vec![Tracker::Udp(other)],
+            ],
+        };
+        let req = TrackerRequest::new([1; 20], [2; 20], 1000);
+        let intervals = |responses: Vec<Result<TrackerResponse, TrackerError>>| {
+            responses
+                .into_iter()
+                .map(|res| res.unwrap().interval)
+                .collect::<Vec<_>>()
+        };
+
+        let servers = async {
+            tokio::join!(
+                async {
+                    first.accept_connect().await;
+                    first.accept_announce(900).await;
+                },
+                async {
+                    second.accept_connect().await;
+                    second.accept_announce(1800).await;
+                }
+            )
+        };
+        let (responses, _) = tokio::join!(trackers.announce(&req), servers);
+        assert_eq!(intervals(responses), [900, 1800]);
+        assert_ne!(trackers.tiers[0][0].url(), url);
+
+        // straight to the tracker that answered, with the connection ids
+        // from last time
+        let servers =
+            async { tokio::join!(first.accept_announce(900), second.accept_announce(1800)) };
+        let (responses, _) = tokio::join!(trackers.announce(&req), servers);
+        assert_eq!(intervals(responses), [900, 1800]);
+        assert_eq!(connects(&silent), 3);
+    }
 }
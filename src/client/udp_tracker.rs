@@ -0,0 +1,497 @@
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use rand::Rng;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout_at;
+
+use super::tracker::{Event, PeerList, ScrapeStats, TrackerError, TrackerRequest, TrackerResponse};
+use super::types::InfoHash;
+
+// Magic constant identifying the protocol in connect requests (BEP 15).
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// A connection id may be used for a minute after it was handed out.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+// Requests are retransmitted after 15 * 2^n seconds.  The spec goes on up to
+// n = 8, over two hours in all, we'd rather give up after n = 2 and let the
+// next tracker have a go.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRANSMITS: u32 = 2;
+// Only this many info hashes fit in a single scrape request's datagram.
+const MAX_SCRAPE_HASHES: usize = 74;
+const MAX_PACKET_SIZE: usize = 2048;
+
+// A tracker speaking the UDP tracker protocol.  Connection ids are cached
+// between requests, so the same instance should be reused for every announce
+// to a given tracker.
+#[derive(Debug)]
+pub struct UdpTracker {
+    url: String,
+    socket: Option<UdpSocket>,
+    connection: Option<(u64, Instant)>,
+    retransmit_timeout: Duration,
+}
+
+impl UdpTracker {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: String::from(url),
+            socket: None,
+            connection: None,
+            retransmit_timeout: RETRANSMIT_TIMEOUT,
+        }
+    }
+
+    // Overrides the base of the retransmit schedule, the spec's 15 seconds
+    // is far too slow for talking to a tracker on the local machine.
+    pub fn with_retransmit_timeout(mut self, retransmit_timeout: Duration) -> Self {
+        self.retransmit_timeout = retransmit_timeout;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn announce(
+        &mut self,
+        req: &TrackerRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut body = BytesMut::with_capacity(82);
+        body.put_slice(&req.info_hash);
+        body.put_slice(&req.peer_id);
+        body.put_u64(req.downloaded);
+        body.put_u64(req.left);
+        body.put_u64(req.uploaded);
+        body.put_u32(match req.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        });
+        // ip address: let the tracker use the packet's source address
+        body.put_u32(0);
+        body.put_u32(req.key);
+        body.put_u32(req.numwant);
+        body.put_u16(req.port as u16);
+
+        let response = self.request(ACTION_ANNOUNCE, &body).await?;
+        if response.len() < 20 {
+            return Err(TrackerError::InvalidResponse(format!(
+                "announce response too short: {} bytes",
+                response.len()
+            )));
+        }
+        // The tracker answers with peers of the same family as the address
+        // it was reached on, so announces over IPv6 return 18 byte entries.
+        let entries = Bytes::copy_from_slice(&response[20..]);
+        let over_ipv6 = self
+            .socket
+            .as_ref()
+            .and_then(|socket| socket.peer_addr().ok())
+            .is_some_and(|addr| addr.is_ipv6());
+        let (peers, peers6) = if over_ipv6 {
+            (PeerList::default(), entries)
+        } else {
+            (PeerList::Compact(entries), Bytes::new())
+        };
+        Ok(TrackerResponse {
+            failure_reason: None,
+            warning_message: None,
+            interval: BigEndian::read_u32(&response[8..12]),
+            min_interval: None,
+            tracker_id: None,
+            incomplete: Some(BigEndian::read_u32(&response[12..16])),
+            complete: Some(BigEndian::read_u32(&response[16..20])),
+            peers,
+            peers6,
+        })
+    }
+
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[InfoHash],
+    ) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let body = chunk.concat();
+            let response = self.request(ACTION_SCRAPE, &body).await?;
+            let entries = &response[8..];
+            if entries.len() < chunk.len() * 12 {
+                return Err(TrackerError::InvalidResponse(format!(
+                    "scrape response too short: {} bytes",
+                    response.len()
+                )));
+            }
+            stats.extend(
+                entries
+                    .chunks_exact(12)
+                    .take(chunk.len())
+                    .map(|entry| ScrapeStats {
+                        complete: BigEndian::read_u32(&entry[0..4]),
+                        downloaded: BigEndian::read_u32(&entry[4..8]),
+                        incomplete: BigEndian::read_u32(&entry[8..12]),
+                    }),
+            );
+        }
+        Ok(stats)
+    }
+
+    fn connection_id(&self) -> Option<u64> {
+        match self.connection {
+            Some((id, obtained)) if obtained.elapsed() < CONNECTION_ID_TTL => Some(id),
+            _ => None,
+        }
+    }
+
+    // Sends `body` with the given action, (re)connecting first whenever the
+    // cached connection id is missing or expired.  Every round trip, connect
+    // or otherwise, waits 15 * 2^n seconds for an answer before moving on to
+    // the next n.
+    async fn request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>, TrackerError> {
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => self.bind().await?,
+        };
+        let result = self.request_on(&socket, action, body).await;
+        self.socket = Some(socket);
+        result
+    }
+
+    async fn request_on(
+        &mut self,
+        socket: &UdpSocket,
+        action: u32,
+        body: &[u8],
+    ) -> Result<Vec<u8>, TrackerError> {
+        for n in 0..=MAX_RETRANSMITS {
+            let wait = self.retransmit_timeout * 2_u32.pow(n);
+            let connection_id = match self.connection_id() {
+                Some(id) => id,
+                None => match round_trip(socket, PROTOCOL_ID, ACTION_CONNECT, &[], wait).await? {
+                    Some(response) if response.len() >= 16 => {
+                        let id = BigEndian::read_u64(&response[8..16]);
+                        self.connection = Some((id, Instant::now()));
+                        id
+                    }
+                    Some(_) => {
+                        return Err(TrackerError::InvalidResponse(String::from(
+                            "connect response too short",
+                        )))
+                    }
+                    None => continue,
+                },
+            };
+            if let Some(response) = round_trip(socket, connection_id, action, body, wait).await? {
+                return Ok(response);
+            }
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    async fn bind(&self) -> Result<UdpSocket, TrackerError> {
+        let invalid_url = || TrackerError::InvalidUrl(self.url.clone());
+        let url = reqwest::Url::parse(&self.url).map_err(|_| invalid_url())?;
+        // IPv6 literals keep their brackets in the url, which the resolver
+        // doesn't accept.
+        let host = url
+            .host_str()
+            .ok_or_else(invalid_url)?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = url.port().ok_or_else(invalid_url)?;
+        let addr = lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(invalid_url)?;
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(socket)
+    }
+}
+
+// Sends a single request and waits up to `wait` for the matching response,
+// returning `None` if it never arrives.  Datagrams for other transactions are
+// ignored, they are most likely late answers to earlier retransmits.
+async fn round_trip(
+    socket: &UdpSocket,
+    connection_id: u64,
+    action: u32,
+    body: &[u8],
+    wait: Duration,
+) -> Result<Option<Vec<u8>>, TrackerError> {
+    let transaction_id: u32 = rand::thread_rng().gen();
+    let mut packet = BytesMut::with_capacity(16 + body.len());
+    packet.put_u64(connection_id);
+    packet.put_u32(action);
+    packet.put_u32(transaction_id);
+    packet.put_slice(body);
+    socket.send(&packet).await?;
+
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    loop {
+        let len = match timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(len) => len?,
+            Err(_) => return Ok(None),
+        };
+        if len < 8 || BigEndian::read_u32(&buf[4..8]) != transaction_id {
+            continue;
+        }
+        let response_action = BigEndian::read_u32(&buf[0..4]);
+        if response_action == ACTION_ERROR {
+            let message = String::from_utf8_lossy(&buf[8..len]);
+            return Err(TrackerError::Failure(message.into_owned()));
+        }
+        if response_action != action {
+            return Err(TrackerError::InvalidResponse(format!(
+                "tracker answered action {} with action {}",
+                action, response_action
+            )));
+        }
+        return Ok(Some(buf[..len].to_vec()));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    const CONNECTION_ID: u64 = 0x1234_5678_9abc_def0;
+
+    // A tracker on the loopback interface whose every answer is scripted by
+    // the test.
+    pub(crate) struct StandIn {
+        socket: UdpSocket,
+    }
+
+    impl StandIn {
+        pub(crate) async fn bind() -> (Self, UdpTracker) {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+            let tracker = UdpTracker::new(&url).with_retransmit_timeout(Duration::from_millis(50));
+            (Self { socket }, tracker)
+        }
+
+        // Waits for the next request, returning its connection id, action,
+        // transaction id and body.
+        async fn recv(&self) -> (u64, u32, u32, Vec<u8>, SocketAddr) {
+            let mut buf = vec![0; MAX_PACKET_SIZE];
+            let (len, from) = self.socket.recv_from(&mut buf).await.unwrap();
+            assert!(len >= 16);
+            (
+                BigEndian::read_u64(&buf[0..8]),
+                BigEndian::read_u32(&buf[8..12]),
+                BigEndian::read_u32(&buf[12..16]),
+                buf[16..len].to_vec(),
+                from,
+            )
+        }
+
+        async fn reply(&self, to: SocketAddr, action: u32, transaction: u32, body: &[u8]) {
+            let mut packet = BytesMut::new();
+            packet.put_u32(action);
+            packet.put_u32(transaction);
+            packet.put_slice(body);
+            self.socket.send_to(&packet, to).await.unwrap();
+        }
+
+        // Answers a connect request with `CONNECTION_ID`.
+        pub(crate) async fn accept_connect(&self) {
+            let (connection, action, transaction, _, from) = self.recv().await;
+            assert_eq!(connection, PROTOCOL_ID);
+            assert_eq!(action, ACTION_CONNECT);
+            self.reply(
+                from,
+                ACTION_CONNECT,
+                transaction,
+                &CONNECTION_ID.to_be_bytes(),
+            )
+            .await;
+        }
+
+        // Answers an announce with `interval` and no peers, returning the
+        // announce's event.
+        pub(crate) async fn accept_announce(&self, interval: u32) -> u32 {
+            let (connection, action, transaction, body, from) = self.recv().await;
+            assert_eq!(connection, CONNECTION_ID);
+            assert_eq!(action, ACTION_ANNOUNCE);
+            let mut reply = BytesMut::new();
+            reply.put_u32(interval);
+            reply.put_u32(0);
+            reply.put_u32(0);
+            self.reply(from, ACTION_ANNOUNCE, transaction, &reply).await;
+            BigEndian::read_u32(&body[64..68])
+        }
+    }
+
+    fn request() -> TrackerRequest {
+        TrackerRequest::new([1; 20], [2; 20], 1000).with_event(Event::Started)
+    }
+
+    #[tokio::test]
+    async fn connect_then_announce() {
+        let (stand_in, mut tracker) = StandIn::bind().await;
+        let server = async {
+            stand_in.accept_connect().await;
+            let (connection, action, transaction, body, from) = stand_in.recv().await;
+            assert_eq!(connection, CONNECTION_ID);
+            assert_eq!(action, ACTION_ANNOUNCE);
+            assert_eq!(&body[0..20], &[1; 20]);
+            assert_eq!(&body[20..40], &[2; 20]);
+            // left, then event
+            assert_eq!(BigEndian::read_u64(&body[48..56]), 1000);
+            assert_eq!(BigEndian::read_u32(&body[64..68]), 2);
+            let mut reply = BytesMut::new();
+            reply.put_u32(1800);
+            reply.put_u32(3);
+            reply.put_u32(5);
+            reply.put_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+            stand_in
+                .reply(from, ACTION_ANNOUNCE, transaction, &reply)
+                .await;
+        };
+        let req = request();
+        let (response, _) = tokio::join!(tracker.announce(&req), server);
+        let response = response.unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.incomplete, Some(3));
+        assert_eq!(response.complete, Some(5));
+        let peers = response.to_peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].socket_addr, "10.0.0.1:6881".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn connection_id_is_reused() {
+        let (stand_in, mut tracker) = StandIn::bind().await;
+        let server = async {
+            stand_in.accept_connect().await;
+            for _ in 0..2 {
+                let (connection, action, transaction, _, from) = stand_in.recv().await;
+                assert_eq!(connection, CONNECTION_ID);
+                assert_eq!(action, ACTION_ANNOUNCE);
+                stand_in
+                    .reply(from, ACTION_ANNOUNCE, transaction, &[0; 12])
+                    .await;
+            }
+        };
+        let client = async {
+            tracker.announce(&request()).await.unwrap();
+            tracker.announce(&request()).await.unwrap();
+        };
+        tokio::join!(client, server);
+    }
+
+    #[tokio::test]
+    async fn scrape() {
+        let (stand_in, mut tracker) = StandIn::bind().await;
+        let server = async {
+            stand_in.accept_connect().await;
+            let (_, action, transaction, body, from) = stand_in.recv().await;
+            assert_eq!(action, ACTION_SCRAPE);
+            assert_eq!(body, [[1; 20], [2; 20]].concat());
+            let mut reply = BytesMut::new();
+            for n in [1, 2, 3, 4, 5, 6] {
+                reply.put_u32(n);
+            }
+            stand_in
+                .reply(from, ACTION_SCRAPE, transaction, &reply)
+                .await;
+        };
+        let (stats, _) = tokio::join!(tracker.scrape(&[[1; 20], [2; 20]]), server);
+        assert_eq!(
+            stats.unwrap(),
+            vec![
+                ScrapeStats {
+                    complete: 1,
+                    downloaded: 2,
+                    incomplete: 3
+                },
+                ScrapeStats {
+                    complete: 4,
+                    downloaded: 5,
+                    incomplete: 6
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_other_transactions() {
+        let (stand_in, mut tracker) = StandIn::bind().await;
+        let server = async {
+            let (_, _, transaction, _, from) = stand_in.recv().await;
+            // a late answer to some earlier request, then a runt
+            stand_in
+                .reply(
+                    from,
+                    ACTION_CONNECT,
+                    transaction.wrapping_add(1),
+                    &[0xff; 8],
+                )
+                .await;
+            stand_in.socket.send_to(&[0; 4], from).await.unwrap();
+            stand_in
+                .reply(
+                    from,
+                    ACTION_CONNECT,
+                    transaction,
+                    &CONNECTION_ID.to_be_bytes(),
+                )
+                .await;
+            let (connection, _, transaction, _, from) = stand_in.recv().await;
+            assert_eq!(connection, CONNECTION_ID);
+            stand_in
+                .reply(from, ACTION_ANNOUNCE, transaction, &[0; 12])
+                .await;
+        };
+        let req = request();
+        let (response, _) = tokio::join!(tracker.announce(&req), server);
+        response.unwrap();
+    }
+
+    #[tokio::test]
+    async fn error_action_is_a_failure() {
+        let (stand_in, mut tracker) = StandIn::bind().await;
+        let server = async {
+            stand_in.accept_connect().await;
+            let (_, _, transaction, _, from) = stand_in.recv().await;
+            stand_in
+                .reply(from, ACTION_ERROR, transaction, b"torrent not registered")
+                .await;
+        };
+        let req = request();
+        let (response, _) = tokio::join!(tracker.announce(&req), server);
+        assert!(matches!(
+            response,
+            Err(TrackerError::Failure(message)) if message == "torrent not registered"
+        ));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retransmits() {
+        let (stand_in, mut tracker) = StandIn::bind().await;
+        let response = tracker.announce(&request()).await;
+        assert!(matches!(response, Err(TrackerError::Timeout)));
+        // every attempt was a connect, none of them answered
+        for _ in 0..=MAX_RETRANSMITS {
+            let (connection, action, ..) = stand_in.recv().await;
+            assert_eq!(connection, PROTOCOL_ID);
+            assert_eq!(action, ACTION_CONNECT);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use super::block::BlockInfo;
+use super::extension::REQUEST_QUEUE_SIZE;
+use super::message::Message;
+use super::peerclient::PeerClient;
+use super::types::Bitfield;
+use super::{PieceWork, WorkerContext, MAX_REQUEST_SIZE};
+
+use anyhow::{anyhow, Result};
+
+// Queues a block the peer asked for, unless it's one we can't or won't send.
+// Bad requests are dropped rather than treated as fatal, a peer may simply
+// not have seen our latest choke yet.
+pub(crate) fn queue_request(client: &mut PeerClient, context: &WorkerContext, block: BlockInfo) {
+    let checked = check_request(
+        client,
+        &context.pieces,
+        &context.own_pieces.borrow(),
+        &block,
+    );
+    if let Err(e) = checked {
+        println!("ignoring request from {}: {}", client.peer, e);
+        return;
+    }
+    if !client.requests.contains(&block) {
+        client.requests.push_back(block);
+    }
+}
+
+pub(crate) fn cancel_request(client: &mut PeerClient, block: BlockInfo) {
+    client.requests.retain(|queued| *queued != block);
+}
+
+fn check_request(
+    client: &PeerClient,
+    pieces: &[PieceWork],
+    own_pieces: &Bitfield,
+    block: &BlockInfo,
+) -> Result<()> {
+    if client.am_choking {
+        return Err(anyhow!("peer is choked"));
+    }
+    // we told the peer how many requests we'd queue in our handshake
+    if client.requests.len() >= REQUEST_QUEUE_SIZE {
+        return Err(anyhow!("request queue is full"));
+    }
+    let piece = pieces
+        .get(block.piece_index)
+        .ok_or_else(|| anyhow!("no piece {}", block.piece_index))?;
+    if !own_pieces[block.piece_index] {
+        return Err(anyhow!("we don't have piece {}", block.piece_index));
+    }
+    let length = block.block_length as usize;
+    if length == 0 || length > MAX_REQUEST_SIZE {
+        return Err(anyhow!("bad block length {}", length));
+    }
+    if block.block_offset as usize + length > piece.length {
+        return Err(anyhow!(
+            "block at {} runs past the end of piece {}",
+            block.block_offset,
+            block.piece_index
+        ));
+    }
+    Ok(())
+}
+
+// Sends the oldest block still queued, reading it from storage off the
+// executor.  One at a time, so the peer can still cancel the rest.
+pub(crate) async fn serve_request(client: &mut PeerClient, context: &WorkerContext) -> Result<()> {
+    let block = match client.requests.pop_front() {
+        Some(block) => block,
+        None => return Ok(()),
+    };
+    let storage = context.storage.clone();
+    let data = tokio::task::spawn_blocking(move || {
+        storage.read(
+            block.piece_index,
+            block.block_offset as usize,
+            block.block_length as usize,
+        )
+    })
+    .await??;
+    let len = data.len();
+    client
+        .send_message(Message::Block {
+            piece_index: block.piece_index,
+            offset: block.block_offset,
+            block_data: data,
+        })
+        .await?;
+    context.stats.add_uploaded(len as u64);
+    context.peer.add_uploaded(len as u64);
+    Ok(())
+}
+
+// Chokes or unchokes the peer if the choker has changed its mind.  Choking
+// throws away whatever the peer asked for, it has to ask again once it's
+// unchoked.
+pub(crate) async fn update_choke(client: &mut PeerClient, context: &WorkerContext) -> Result<()> {
+    let choke = context.peer.should_choke();
+    if choke == client.am_choking {
+        return Ok(());
+    }
+    if choke {
+        client.send_message(Message::Choke).await?;
+        client.requests.clear();
+    } else {
+        client.send_message(Message::Unchoke).await?;
+    }
+    client.am_choking = choke;
+    Ok(())
+}
+
+// Tells the peer about pieces we've finished since we last told it anything.
+pub(crate) async fn announce_pieces(
+    client: &mut PeerClient,
+    context: &WorkerContext,
+) -> Result<()> {
+    let new: Vec<usize> = context
+        .own_pieces
+        .borrow()
+        .iter_ones()
+        .filter(|index| !client.own_pieces[*index])
+        .collect();
+    for piece_index in new {
+        client.send_message(Message::Have { piece_index }).await?;
+        client.own_pieces.set(piece_index, true);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::peerclient::tests::connect;
+    use crate::client::torrent::test_helpers::bitfield;
+    use crate::client::torrent::TorrentFile;
+
+    // Two pieces of 2 blocks, the second one short, and we only have the
+    // first.
+    fn pieces() -> (Vec<PieceWork>, Bitfield) {
+        let torrent = TorrentFile::for_tests(
+            2 * MAX_REQUEST_SIZE,
+            &[&vec![0; 3 * MAX_REQUEST_SIZE], &[0; 10]],
+        );
+        (PieceWork::all(&torrent), bitfield(&[true, false]))
+    }
+
+    fn block(piece_index: usize, offset: usize, length: usize) -> BlockInfo {
+        BlockInfo {
+            piece_index,
+            block_offset: offset as u32,
+            block_length: length as u32,
+        }
+    }
+
+    async fn unchoked_client() -> PeerClient {
+        let (mut client, _) = connect().await;
+        client.am_choking = false;
+        client
+    }
+
+    #[tokio::test]
+    async fn requests_we_can_serve() {
+        let client = unchoked_client().await;
+        let (pieces, own_pieces) = pieces();
+        for block in [
+            block(0, 0, MAX_REQUEST_SIZE),
+            block(0, MAX_REQUEST_SIZE, MAX_REQUEST_SIZE),
+            block(0, 10, 1),
+        ] {
+            check_request(&client, &pieces, &own_pieces, &block).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn bad_requests() {
+        let client = unchoked_client().await;
+        let (pieces, own_pieces) = pieces();
+        for block in [
+            // out of range
+            block(2, 0, MAX_REQUEST_SIZE),
+            // one we don't have
+            block(1, 0, 10),
+            // past the end of the piece
+            block(0, MAX_REQUEST_SIZE + 1, MAX_REQUEST_SIZE),
+            block(0, 2 * MAX_REQUEST_SIZE, 1),
+            // too big, or nothing at all
+            block(0, 0, MAX_REQUEST_SIZE + 1),
+            block(0, 0, 0),
+        ] {
+            assert!(
+                check_request(&client, &pieces, &own_pieces, &block).is_err(),
+                "{:?} was accepted",
+                block
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn nothing_while_choked() {
+        let (client, _) = connect().await;
+        let (pieces, own_pieces) = pieces();
+        assert!(client.am_choking);
+        assert!(check_request(&client, &pieces, &own_pieces, &block(0, 0, 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_requests() {
+        let mut client = unchoked_client().await;
+        let (pieces, own_pieces) = pieces();
+        for offset in 0..REQUEST_QUEUE_SIZE {
+            client.requests.push_back(block(0, offset, 1));
+        }
+        let extra = block(0, REQUEST_QUEUE_SIZE, 1);
+        assert!(check_request(&client, &pieces, &own_pieces, &extra).is_err());
+        client.requests.pop_back();
+        check_request(&client, &pieces, &own_pieces, &extra).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use super::storage::Storage;
+use super::torrent::TorrentFile;
+use super::PieceWork;
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    // The data matches the piece's hash.
+    Complete,
+    // There's data but it doesn't match.
+    Corrupt,
+    // Some of the piece's data isn't there at all.
+    Missing,
+}
+
+// A file is only as good as the pieces it's part of.  Pieces at either end
+// are shared with the neighbouring files, so a bad neighbour can make an
+// intact file look corrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Complete,
+    // At least one of the file's pieces is corrupt.
+    Corrupt,
+    // None are corrupt, but some are missing.
+    Missing,
+}
+
+#[derive(Debug)]
+pub struct VerifyReport {
+    // In piece order.
+    pub pieces: Vec<PieceStatus>,
+    // In the same order as the torrent's files.
+    pub files: Vec<FileStatus>,
+}
+
+impl VerifyReport {
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|piece| *piece == PieceStatus::Complete)
+    }
+}
+
+// Checks every piece of the torrent that `storage` holds against its hash,
+// without going anywhere near the network.
+pub async fn verify(torrent_file: &TorrentFile, storage: Arc<dyn Storage>) -> Result<VerifyReport> {
+    storage.open(torrent_file)?;
+    let pieces = check_pieces(torrent_file, storage).await?;
+    let files = (0..torrent_file.files().len())
+        .map(|file_index| {
+            let statuses =
+                piece_range_for_file(torrent_file, file_index).map(|index| pieces[index]);
+            statuses.fold(FileStatus::Complete, |file, piece| match (file, piece) {
+                (FileStatus::Corrupt, _) | (_, PieceStatus::Corrupt) => FileStatus::Corrupt,
+                (FileStatus::Missing, _) | (_, PieceStatus::Missing) => FileStatus::Missing,
+                _ => FileStatus::Complete,
+            })
+        })
+        .collect();
+    Ok(VerifyReport { pieces, files })
+}
+
+// Hashes every piece, spread over as many threads as there are cores.
+pub(crate) async fn check_pieces(
+    torrent_file: &TorrentFile,
+    storage: Arc<dyn Storage>,
+) -> Result<Vec<PieceStatus>> {
+    let work = Arc::new(PieceWork::all(torrent_file));
+    let statuses = Arc::new(Mutex::new(vec![PieceStatus::Missing; work.len()]));
+    let next = Arc::new(AtomicUsize::new(0));
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut hashers = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        let (work, statuses, next, storage) = (
+            work.clone(),
+            statuses.clone(),
+            next.clone(),
+            storage.clone(),
+        );
+        hashers.push(tokio::task::spawn_blocking(move || {
+            while let Some(piece) = work.get(next.fetch_add(1, Ordering::Relaxed)) {
+                let status = check_piece(piece, storage.as_ref());
+                statuses.lock().unwrap()[piece.index] = status;
+            }
+        }));
+    }
+    for hasher in hashers {
+        hasher.await?;
+    }
+    let statuses = statuses.lock().unwrap().clone();
+    Ok(statuses)
+}
+
+// Data that can't be read back was never written.  Neither was data that's
+// nothing but zeroes, that's what the gaps between pieces written out of
+// order read as.
+fn check_piece(piece: &PieceWork, storage: &dyn Storage) -> PieceStatus {
+    match storage.read(piece.index, 0, piece.length) {
+        Ok(data) if piece.check_integrity(&data) => PieceStatus::Complete,
+        Ok(data) if data.iter().any(|byte| *byte != 0) => PieceStatus::Corrupt,
+        _ => PieceStatus::Missing,
+    }
+}
+
+fn piece_range_for_file(torrent_file: &TorrentFile, file_index: usize) -> std::ops::Range<usize> {
+    let file = &torrent_file.files()[file_index];
+    let piece_length = torrent_file.info.piece_length;
+    let first = file.offset / piece_length;
+    // empty files don't touch any piece
+    if file.length == 0 {
+        return first..first;
+    }
+    let last = (file.offset + file.length - 1) / piece_length;
+    first..last + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::storage::{FileStorage, MemoryStorage};
+    use std::fs;
+
+    // Pieces of 16 bytes, the first two shared by the first two files and
+    // the last one all in the third.
+    fn torrent() -> TorrentFile {
+        TorrentFile::for_tests(16, &[&[1; 20], &[2; 12], &[3; 16]])
+    }
+
+    // Piece 0 as it should be, piece 1 corrupt and nothing of the third file.
+    fn storage(torrent: &TorrentFile) -> Arc<dyn Storage> {
+        let storage = MemoryStorage::new();
+        storage.open(torrent).unwrap();
+        let mut piece = [1; 16];
+        storage.write(0, 0, &piece).unwrap();
+        piece[..4].copy_from_slice(&[1; 4]);
+        piece[4..].copy_from_slice(&[9; 12]);
+        storage.write(1, 0, &piece).unwrap();
+        Arc::new(storage)
+    }
+
+    #[tokio::test]
+    async fn pieces_are_checked() {
+        let torrent = torrent();
+        let statuses = check_pieces(&torrent, storage(&torrent)).await.unwrap();
+        assert_eq!(
+            statuses,
+            [
+                PieceStatus::Complete,
+                PieceStatus::Corrupt,
+                PieceStatus::Missing
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn zeroes_are_missing() {
+        let torrent = torrent();
+        let storage = MemoryStorage::new();
+        storage.open(&torrent).unwrap();
+        // piece 0 reads back as zeroes once a later piece is written
+        storage.write(1, 0, &[0; 16]).unwrap();
+        let statuses = check_pieces(&torrent, Arc::new(storage)).await.unwrap();
+        assert_eq!(statuses, [PieceStatus::Missing; 3]);
+    }
+
+    #[tokio::test]
+    async fn files_take_the_worst_of_their_pieces() {
+        let torrent = torrent();
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("test")).unwrap();
+        fs::write(dir.path().join("test/f0"), [1; 20]).unwrap();
+        fs::write(dir.path().join("test/f1"), [9; 12]).unwrap();
+        let report = verify(&torrent, Arc::new(FileStorage::read_only(dir.path())))
+            .await
+            .unwrap();
+        assert!(!report.is_complete());
+        assert_eq!(
+            report.pieces,
+            [
+                PieceStatus::Complete,
+                PieceStatus::Corrupt,
+                PieceStatus::Missing
+            ]
+        );
+        assert_eq!(
+            report.files,
+            [
+                FileStatus::Corrupt,
+                FileStatus::Corrupt,
+                FileStatus::Missing
+            ]
+        );
+    }
+}
@@ -0,0 +1,16 @@
+extern crate serde_derive;
+
+mod client;
+
+pub use client::listener::PeerListener;
+pub use client::magnet::Magnet;
+pub use client::priority::{FilePriorities, FilePriority};
+pub use client::storage::{FileStorage, MemoryStorage, Storage};
+pub use client::torrent::{FileInfo, FileSlice, Info, MetainfoError, TorrentFile};
+pub use client::tracker::{
+    AnnounceList, PeerDictionary, PeerList, ScrapeStats, Tracker, TrackerError, TrackerRequest,
+    TrackerResponse,
+};
+pub use client::udp_tracker::UdpTracker;
+pub use client::verify::{verify, FileStatus, PieceStatus, VerifyReport};
+pub use client::LeechClient;
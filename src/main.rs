@@ -1,14 +1,68 @@
-extern crate serde_derive;
 use anyhow::Result;
 
-mod client;
-use client::LeechClient;
+use leech::{verify, FileStorage, LeechClient, TorrentFile};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let filename = "debian-mac-11.2.0-amd64-netinst.iso.torrent";
-    let client = LeechClient::new(filename).await?;
+    let mut args = std::env::args().skip(1);
+    let source = args
+        .next()
+        .unwrap_or_else(|| String::from("debian-mac-11.2.0-amd64-netinst.iso.torrent"));
+    // leech --verify <torrent> [dir] checks data that's already on disk
+    // without downloading anything.
+    if source == "--verify" {
+        let torrent = args
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no torrent given"))?;
+        let dir = args.next().unwrap_or_else(|| String::from("."));
+        return verify_existing(&torrent, &dir).await;
+    }
+
+    // downloads land in the current directory
+    let storage = Arc::new(FileStorage::new("."));
+    let mut client = if source.starts_with("magnet:") {
+        LeechClient::from_magnet(&source, storage).await?
+    } else {
+        LeechClient::new(&source, storage).await?
+    };
+    // --seed-ratio <ratio> and --seed-time <seconds> keep uploading once
+    // the download is done, --connect-timeout, --handshake-timeout and
+    // --inactivity-timeout <seconds> set how long we wait on peers
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no value given for {}", flag))?;
+        client = match flag.as_str() {
+            "--seed-ratio" => client.with_seed_ratio(value.parse()?),
+            "--seed-time" => client.with_seed_time(Duration::from_secs(value.parse()?)),
+            "--connect-timeout" => client.with_connect_timeout(Duration::from_secs(value.parse()?)),
+            "--handshake-timeout" => {
+                client.with_handshake_timeout(Duration::from_secs(value.parse()?))
+            }
+            "--inactivity-timeout" => {
+                client.with_inactivity_timeout(Duration::from_secs(value.parse()?))
+            }
+            _ => return Err(anyhow::anyhow!("unknown option {}", flag)),
+        };
+    }
     println!("{:?}", client);
     client.download().await?;
     Ok(())
 }
+
+async fn verify_existing(torrent: &str, dir: &str) -> Result<()> {
+    let torrent_file = TorrentFile::from_path(torrent)?;
+    let report = verify(&torrent_file, Arc::new(FileStorage::read_only(dir))).await?;
+    for (index, status) in report.pieces.iter().enumerate() {
+        println!("piece {}: {:?}", index, status);
+    }
+    for (file, status) in torrent_file.files().iter().zip(&report.files) {
+        println!("{}: {:?}", file.path.display(), status);
+    }
+    if report.is_complete() {
+        println!("all {} pieces are complete", report.pieces.len());
+    }
+    Ok(())
+}